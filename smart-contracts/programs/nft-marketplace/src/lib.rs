@@ -1,22 +1,70 @@
+#![allow(clippy::result_large_err)]
+
 use anchor_lang::prelude::*;
+use anchor_lang::solana_program::{keccak, program::invoke_signed};
 use anchor_spl::{
     associated_token::AssociatedToken,
-    token::{Mint, Token, TokenAccount},
+    token_2022::spl_token_2022::{
+        self,
+        extension::{non_transferable::NonTransferable, BaseStateWithExtensions, StateWithExtensions},
+    },
+    token_interface::{Mint, TokenAccount, TokenInterface},
+};
+use mpl_bubblegum::{
+    hash::{hash_creators, hash_metadata},
+    instructions::{
+        DelegateCpi, DelegateCpiAccounts, DelegateInstructionArgs, TransferCpi, TransferCpiAccounts,
+        TransferInstructionArgs,
+    },
+    types::MetadataArgs,
 };
-use solana_program::program_option::COption;
+use mpl_token_metadata::{
+    instruction::{builders, InstructionBuilder, TransferArgs},
+    state::{Metadata, TokenMetadataAccount, TokenStandard},
+};
+use spl_token_metadata_interface::state::TokenMetadata;
 
 declare_id!("Fg6PaFpoGXkYsidMpWTK6W2BeZ7FEfcYkg476zPFsLnS");
 
+// Maximum number of SPL token mints a marketplace accepts as payment
+pub const MAX_PAYMENT_MINTS: usize = 8;
+
+// Maximum length in bytes of a marketplace name, which must fit in a PDA seed
+pub const MAX_MARKETPLACE_NAME_LEN: usize = 32;
+
+// Maximum number of NFTs sold together in a bundle
+pub const MAX_BUNDLE_SIZE: usize = 10;
+
+// Maximum number of NFTs on each side of a swap
+pub const MAX_SWAP_SIZE: usize = 5;
+
+// Lamports paid out of an expired listing's rent to whoever closes it
+pub const LISTING_EXPIRY_REWARD: u64 = 100_000;
+
+// Longest an auction may run, and longest a late bid may extend it, in seconds
+pub const MAX_AUCTION_DURATION: i64 = 30 * 24 * 60 * 60;
+
 #[program]
 pub mod nft_marketplace {
     use super::*;
     
     // Events for tracking marketplace actions
+    #[event]
+    pub struct ListingUpdated {
+        pub seller: Pubkey,
+        pub mint: Pubkey,
+        pub old_price: u64,
+        pub new_price: u64,
+        pub old_payment_mint: Option<Pubkey>,
+        pub new_payment_mint: Option<Pubkey>,
+    }
+
     #[event]
     pub struct BidPlaced {
         pub bidder: Pubkey,
         pub mint: Pubkey,
         pub price: u64,
+        pub payment_mint: Option<Pubkey>,
         pub expires_at: i64,
     }
 
@@ -35,112 +83,1249 @@ pub mod nft_marketplace {
         pub price: u64,
     }
 
+    #[event]
+    pub struct BundleSold {
+        pub seller: Pubkey,
+        pub buyer: Pubkey,
+        pub mints: Vec<Pubkey>,
+        pub price: u64,
+        pub item_fees: Vec<u64>,
+    }
+
+    #[event]
+    pub struct SwapExecuted {
+        pub swap: Pubkey,
+        pub proposer: Pubkey,
+        pub counterparty: Pubkey,
+        pub offered_mints: Vec<Pubkey>,
+        pub offered_lamports: u64,
+        pub requested_mints: Vec<Pubkey>,
+        pub requested_lamports: u64,
+    }
+
+    #[event]
+    pub struct AuctionBidPlaced {
+        pub auction: Pubkey,
+        pub bidder: Pubkey,
+        pub amount: u64,
+        pub end_time: i64,
+    }
+
+    #[event]
+    pub struct AuctionSettled {
+        pub auction: Pubkey,
+        pub seller: Pubkey,
+        pub winner: Option<Pubkey>,
+        pub price: u64,
+    }
+
     // Initialize a new marketplace
+    //
+    // Marketplaces are addressed by their creating authority and `name`, so one
+    // deployment can host several. `max_fee` caps the maker and taker fees
+    // for the lifetime of the marketplace; all are in basis points. A negative
+    // `maker_fee` pays makers a rebate out of the taker fee.
     pub fn initialize_marketplace(
         ctx: Context<InitializeMarketplace>,
-        marketplace_fee: u64,
+        name: String,
+        maker_fee: i64,
+        taker_fee: u64,
+        max_fee: u64,
     ) -> Result<()> {
+        require!(
+            !name.is_empty() && name.len() <= MAX_MARKETPLACE_NAME_LEN,
+            NftMarketplaceError::InvalidMarketplaceName
+        );
+        require!(max_fee <= 10000, NftMarketplaceError::InvalidFee);
+        
         let marketplace = &mut ctx.accounts.marketplace;
         marketplace.authority = ctx.accounts.authority.key();
-        marketplace.fee = marketplace_fee;
+        marketplace.creator = ctx.accounts.authority.key();
+        marketplace.name = name;
+        marketplace.maker_fee = maker_fee;
+        marketplace.taker_fee = taker_fee;
+        marketplace.max_fee = max_fee;
+        require_valid_fees(marketplace.maker_fee, marketplace.taker_fee, marketplace.max_fee)?;
         marketplace.treasury = ctx.accounts.treasury.key();
-        marketplace.bump = *ctx.bumps.get("marketplace").unwrap();
+        marketplace.bump = ctx.bumps.marketplace;
+
+        msg!("Marketplace initialized with maker fee: {} and taker fee: {}", maker_fee, taker_fee);
+        Ok(())
+    }
+
+    // Update the marketplace fees, treasury and/or referral share
+    //
+    // `referral_fee` is the share of the marketplace fee, in basis points, paid
+    // to the registered referrer of a purchase or accepted offer or bid.
+    pub fn update_marketplace(
+        ctx: Context<UpdateMarketplace>,
+        maker_fee: Option<i64>,
+        taker_fee: Option<u64>,
+        treasury: Option<Pubkey>,
+        referral_fee: Option<u64>,
+    ) -> Result<()> {
+        let marketplace = &mut ctx.accounts.marketplace;
+        if let Some(maker_fee) = maker_fee {
+            marketplace.maker_fee = maker_fee;
+        }
+        if let Some(taker_fee) = taker_fee {
+            marketplace.taker_fee = taker_fee;
+        }
+        require_valid_fees(marketplace.maker_fee, marketplace.taker_fee, marketplace.max_fee)?;
+        if let Some(treasury) = treasury {
+            marketplace.treasury = treasury;
+        }
+        if let Some(referral_fee) = referral_fee {
+            require!(referral_fee <= 10000, NftMarketplaceError::InvalidFee);
+            marketplace.referral_fee = referral_fee;
+        }
+        
+        msg!(
+            "Marketplace updated with maker fee: {}, taker fee: {} and treasury: {}",
+            marketplace.maker_fee,
+            marketplace.taker_fee,
+            marketplace.treasury
+        );
+        Ok(())
+    }
+
+    // Propose a new marketplace authority, which takes over once it accepts
+    pub fn propose_authority(
+        ctx: Context<UpdateMarketplace>,
+        new_authority: Pubkey,
+    ) -> Result<()> {
+        let marketplace = &mut ctx.accounts.marketplace;
+        marketplace.pending_authority = Some(new_authority);
+        
+        msg!("Marketplace authority proposed: {}", new_authority);
+        Ok(())
+    }
+
+    // Accept a pending authority handover
+    pub fn accept_authority(ctx: Context<AcceptAuthority>) -> Result<()> {
+        let marketplace = &mut ctx.accounts.marketplace;
+        marketplace.authority = ctx.accounts.new_authority.key();
+        marketplace.pending_authority = None;
+        
+        msg!("Marketplace authority transferred to: {}", marketplace.authority);
+        Ok(())
+    }
+
+    // Replace the SPL token mints accepted as payment alongside SOL
+    pub fn set_payment_mints(
+        ctx: Context<UpdateMarketplace>,
+        payment_mints: Vec<Pubkey>,
+    ) -> Result<()> {
+        require!(
+            payment_mints.len() <= MAX_PAYMENT_MINTS,
+            NftMarketplaceError::TooManyPaymentMints
+        );
+        
+        let marketplace = &mut ctx.accounts.marketplace;
+        marketplace.payment_mints = payment_mints;
+        
+        msg!("Marketplace accepts {} payment mints", marketplace.payment_mints.len());
+        Ok(())
+    }
 
-        msg!("Marketplace initialized with fee: {}", marketplace_fee);
+    // Register a front-end or aggregator wallet eligible for referral fees
+    pub fn register_referrer(ctx: Context<RegisterReferrer>, wallet: Pubkey) -> Result<()> {
+        let referrer = &mut ctx.accounts.referrer;
+        referrer.marketplace = ctx.accounts.marketplace.key();
+        referrer.wallet = wallet;
+        referrer.bump = ctx.bumps.referrer;
+        
+        msg!("Referrer registered: {}", wallet);
+        Ok(())
+    }
+
+    // Deregister a referrer, returning its rent to the authority
+    pub fn remove_referrer(ctx: Context<RemoveReferrer>) -> Result<()> {
+        msg!("Referrer removed: {}", ctx.accounts.referrer.wallet);
         Ok(())
     }
 
     // List an NFT for sale
-    pub fn list(
-        ctx: Context<ListNft>,
+    //
+    // The listing is priced in lamports, or in tokens of `payment_mint` when
+    // that account is provided. Passing `dutch` lists at a price decaying from
+    // its start price to its floor, in which case `price` is ignored. Setting
+    // `reserved_buyer` makes a private listing only that wallet can buy. The
+    // listing cannot be bought after `expiry_time`. Semi-fungible mints may
+    // list several units, with `price` charged per unit. Extra accounts
+    // required by a Token-2022 transfer hook are passed as remaining accounts.
+    #[allow(clippy::too_many_arguments)]
+    pub fn list<'info>(
+        ctx: Context<'_, '_, '_, 'info, ListNft<'info>>,
         price: u64,
-        auction_house_fee: u64,
+        _auction_house_fee: u64,
+        mode: ListingMode,
+        freeze: bool,
+        dutch: Option<DutchAuction>,
+        reserved_buyer: Option<Pubkey>,
+        expiry_time: i64,
+        quantity: u64,
     ) -> Result<()> {
+        let price = match dutch {
+            Some(dutch) => {
+                require!(
+                    dutch.start_price >= dutch.floor_price && dutch.duration > 0,
+                    NftMarketplaceError::InvalidDutchAuction
+                );
+                dutch.start_price
+            }
+            None => price,
+        };
+        let now = Clock::get()?.unix_timestamp;
+        require!(expiry_time > now, NftMarketplaceError::InvalidExpiry);
+        
         let listing = &mut ctx.accounts.listing;
+        listing.marketplace = ctx.accounts.marketplace.key();
         listing.seller = ctx.accounts.seller.key();
         listing.mint = ctx.accounts.mint.key();
         listing.price = price;
         listing.active = true;
-        listing.created_at = Clock::get()?.unix_timestamp;
-        listing.bump = *ctx.bumps.get("listing").unwrap();
+        listing.created_at = now;
+        listing.bump = ctx.bumps.listing;
+        listing.mode = mode;
+        listing.payment_mint = accepted_payment_mint(&ctx.accounts.marketplace, &ctx.accounts.payment_mint)?;
+        listing.dutch = dutch;
+        listing.reserved_buyer = reserved_buyer;
+        listing.expires_at = expiry_time;
+        listing.quantity = quantity;
+
+        require_transferable(&ctx.accounts.mint)?;
+        let programmable = is_programmable(&ctx.accounts.metadata)?;
+        
+        // Apply the policy of the NFT's collection, if configured
+        let collection_config = collection_config(
+            &ctx.accounts.marketplace.key(),
+            &ctx.accounts.metadata,
+            ctx.accounts.collection_config.as_deref(),
+        )?;
+        require_collection_payment_mint(collection_config.as_ref(), listing.payment_mint)?;
+        require_collection_min_price(
+            collection_config.as_ref(),
+            dutch.map_or(price, |dutch| dutch.floor_price),
+        )?;
+        
+        // Only mints without decimals can be listed by the unit, and Token
+        // Metadata can neither freeze nor transfer several units of them
+        require!(
+            quantity > 0 && quantity <= ctx.accounts.seller_token_account.amount,
+            NftMarketplaceError::InvalidQuantity
+        );
+        require!(
+            quantity == 1 || (ctx.accounts.mint.decimals == 0 && !programmable && !freeze),
+            NftMarketplaceError::InvalidQuantity
+        );
 
-        msg!("NFT listed for sale at price: {}", price);
+        match mode {
+            ListingMode::Escrow => {
+                let vault = ctx
+                    .accounts
+                    .vault
+                    .as_ref()
+                    .ok_or(NftMarketplaceError::MissingListingAccount)?;
+                listing.vault_bump = ctx.bumps.vault;
+
+                // Move the NFT into the listing vault
+                if programmable {
+                    transfer_programmable_nft(
+                        ProgrammableTransfer {
+                            token: ctx.accounts.seller_token_account.to_account_info(),
+                            token_owner: ctx.accounts.seller.to_account_info(),
+                            destination: vault.to_account_info(),
+                            destination_owner: vault.to_account_info(),
+                            mint: ctx.accounts.mint.to_account_info(),
+                            metadata: ctx.accounts.metadata.to_account_info(),
+                            edition: required_account(&ctx.accounts.edition)?,
+                            owner_token_record: required_account(&ctx.accounts.owner_token_record)?,
+                            destination_token_record: required_account(&ctx.accounts.destination_token_record)?,
+                            authority: ctx.accounts.seller.to_account_info(),
+                            payer: ctx.accounts.seller.to_account_info(),
+                            system_program: ctx.accounts.system_program.to_account_info(),
+                            sysvar_instructions: required_account(&ctx.accounts.sysvar_instructions)?,
+                            token_program: ctx.accounts.token_program.to_account_info(),
+                            associated_token_program: required_account(&ctx.accounts.associated_token_program)?,
+                            token_metadata_program: required_account(&ctx.accounts.token_metadata_program)?,
+                            authorization_rules_program: optional_account(&ctx.accounts.authorization_rules_program),
+                            authorization_rules: optional_account(&ctx.accounts.authorization_rules),
+                        },
+                        &[],
+                    )?;
+                } else {
+                    transfer_units(
+                        ctx.accounts.token_program.to_account_info(),
+                        ctx.accounts.seller_token_account.to_account_info(),
+                        &ctx.accounts.mint,
+                        vault.to_account_info(),
+                        ctx.accounts.seller.to_account_info(),
+                        ctx.remaining_accounts,
+                        quantity,
+                        &[],
+                    )?;
+                }
+            }
+            ListingMode::Delegate => {
+                // Programmable NFTs are frozen by Token Metadata and cannot be SPL-approved
+                require!(!programmable, NftMarketplaceError::UnsupportedListingMode);
+
+                let delegate = ctx
+                    .accounts
+                    .delegate
+                    .as_ref()
+                    .ok_or(NftMarketplaceError::MissingListingAccount)?;
+                listing.delegate_bump = ctx.bumps.delegate;
+
+                // Approve the listing delegate to move the NFT on sale
+                let cpi_accounts = anchor_spl::token_interface::Approve {
+                    to: ctx.accounts.seller_token_account.to_account_info(),
+                    delegate: delegate.to_account_info(),
+                    authority: ctx.accounts.seller.to_account_info(),
+                };
+                
+                let cpi_program = ctx.accounts.token_program.to_account_info();
+                let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+                
+                anchor_spl::token_interface::approve(cpi_ctx, quantity)?;
+
+                // Optionally freeze the NFT in the seller's wallet through the delegate
+                if freeze {
+                    let edition = ctx
+                        .accounts
+                        .edition
+                        .as_ref()
+                        .ok_or(NftMarketplaceError::MissingListingAccount)?;
+                    let token_metadata_program = ctx
+                        .accounts
+                        .token_metadata_program
+                        .as_ref()
+                        .ok_or(NftMarketplaceError::MissingListingAccount)?;
+
+                    let listing_key = listing.key();
+                    let seeds = &[
+                        b"delegate".as_ref(),
+                        listing_key.as_ref(),
+                        &[listing.delegate_bump],
+                    ];
+                    let signer = &[&seeds[..]];
+
+                    invoke_signed(
+                        &mpl_token_metadata::instruction::freeze_delegated_account(
+                            mpl_token_metadata::ID,
+                            delegate.key(),
+                            ctx.accounts.seller_token_account.key(),
+                            edition.key(),
+                            ctx.accounts.mint.key(),
+                        ),
+                        &[
+                            delegate.to_account_info(),
+                            ctx.accounts.seller_token_account.to_account_info(),
+                            edition.to_account_info(),
+                            ctx.accounts.mint.to_account_info(),
+                            ctx.accounts.token_program.to_account_info(),
+                            token_metadata_program.to_account_info(),
+                        ],
+                        signer,
+                    )?;
+                }
+                listing.frozen = freeze;
+            }
+            ListingMode::Compressed => {
+                return err!(NftMarketplaceError::UnsupportedListingMode);
+            }
+        }
+
+        msg!("{} units listed for sale at price: {}", quantity, price);
         Ok(())
     }
 
-    // Cancel a listing
-    pub fn cancel_listing(ctx: Context<CancelListing>) -> Result<()> {
+    // Reprice an active listing, optionally switching its payment mint
+    pub fn update_listing(ctx: Context<UpdateListing>, price: u64) -> Result<()> {
+        let payment_mint = accepted_payment_mint(&ctx.accounts.marketplace, &ctx.accounts.payment_mint)?;
+        
+        let collection_config = collection_config(
+            &ctx.accounts.marketplace.key(),
+            &ctx.accounts.metadata,
+            ctx.accounts.collection_config.as_deref(),
+        )?;
+        require_collection_payment_mint(collection_config.as_ref(), payment_mint)?;
+        require_collection_min_price(collection_config.as_ref(), price)?;
+        
         let listing = &mut ctx.accounts.listing;
-        listing.active = false;
+        require!(listing.dutch.is_none(), NftMarketplaceError::UnsupportedListingMode);
+        let old_price = listing.price;
+        let old_payment_mint = listing.payment_mint;
+        listing.price = price;
+        listing.payment_mint = payment_mint;
         
+        // Emit event
+        emit!(ListingUpdated {
+            seller: listing.seller,
+            mint: listing.mint,
+            old_price,
+            new_price: price,
+            old_payment_mint,
+            new_payment_mint: payment_mint,
+        });
+        
+        msg!("Listing updated from {} to {}", old_price, price);
+        Ok(())
+    }
+
+    // Cancel a listing
+    pub fn cancel_listing<'info>(
+        ctx: Context<'_, '_, '_, 'info, CancelListing<'info>>,
+    ) -> Result<()> {
+        let listing = &ctx.accounts.listing;
+        let listing_key = listing.key();
+
+        match listing.mode {
+            ListingMode::Escrow => {
+                let vault = ctx
+                    .accounts
+                    .vault
+                    .as_ref()
+                    .ok_or(NftMarketplaceError::MissingListingAccount)?;
+
+                // Return the NFT from the listing vault to the seller
+                let seeds = &[
+                    b"vault".as_ref(),
+                    listing_key.as_ref(),
+                    &[listing.vault_bump],
+                ];
+                let signer = &[&seeds[..]];
+                
+                if is_programmable(&ctx.accounts.metadata)? {
+                    transfer_programmable_nft(
+                        ProgrammableTransfer {
+                            token: vault.to_account_info(),
+                            token_owner: vault.to_account_info(),
+                            destination: ctx.accounts.seller_token_account.to_account_info(),
+                            destination_owner: ctx.accounts.seller.to_account_info(),
+                            mint: ctx.accounts.mint.to_account_info(),
+                            metadata: ctx.accounts.metadata.to_account_info(),
+                            edition: required_account(&ctx.accounts.edition)?,
+                            owner_token_record: required_account(&ctx.accounts.owner_token_record)?,
+                            destination_token_record: required_account(&ctx.accounts.destination_token_record)?,
+                            authority: vault.to_account_info(),
+                            payer: ctx.accounts.seller.to_account_info(),
+                            system_program: ctx.accounts.system_program.to_account_info(),
+                            sysvar_instructions: required_account(&ctx.accounts.sysvar_instructions)?,
+                            token_program: ctx.accounts.token_program.to_account_info(),
+                            associated_token_program: required_account(&ctx.accounts.associated_token_program)?,
+                            token_metadata_program: required_account(&ctx.accounts.token_metadata_program)?,
+                            authorization_rules_program: optional_account(&ctx.accounts.authorization_rules_program),
+                            authorization_rules: optional_account(&ctx.accounts.authorization_rules),
+                        },
+                        signer,
+                    )?;
+                } else {
+                    transfer_units(
+                        ctx.accounts.token_program.to_account_info(),
+                        vault.to_account_info(),
+                        &ctx.accounts.mint,
+                        ctx.accounts.seller_token_account.to_account_info(),
+                        vault.to_account_info(),
+                        ctx.remaining_accounts,
+                        listing.quantity,
+                        signer,
+                    )?;
+                }
+
+                // Close the emptied vault, returning its rent to the seller
+                anchor_spl::token_interface::close_account(CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    anchor_spl::token_interface::CloseAccount {
+                        account: vault.to_account_info(),
+                        destination: ctx.accounts.seller.to_account_info(),
+                        authority: vault.to_account_info(),
+                    },
+                    signer,
+                ))?;
+            }
+            ListingMode::Delegate => {
+                // Thaw the NFT if it was frozen when listed
+                if listing.frozen {
+                    let delegate = ctx
+                        .accounts
+                        .delegate
+                        .as_ref()
+                        .ok_or(NftMarketplaceError::MissingListingAccount)?;
+                    let edition = ctx
+                        .accounts
+                        .edition
+                        .as_ref()
+                        .ok_or(NftMarketplaceError::MissingListingAccount)?;
+                    let token_metadata_program = ctx
+                        .accounts
+                        .token_metadata_program
+                        .as_ref()
+                        .ok_or(NftMarketplaceError::MissingListingAccount)?;
+
+                    let seeds = &[
+                        b"delegate".as_ref(),
+                        listing_key.as_ref(),
+                        &[listing.delegate_bump],
+                    ];
+                    let signer = &[&seeds[..]];
+
+                    invoke_signed(
+                        &mpl_token_metadata::instruction::thaw_delegated_account(
+                            mpl_token_metadata::ID,
+                            delegate.key(),
+                            ctx.accounts.seller_token_account.key(),
+                            edition.key(),
+                            ctx.accounts.mint.key(),
+                        ),
+                        &[
+                            delegate.to_account_info(),
+                            ctx.accounts.seller_token_account.to_account_info(),
+                            edition.to_account_info(),
+                            ctx.accounts.mint.to_account_info(),
+                            ctx.accounts.token_program.to_account_info(),
+                            token_metadata_program.to_account_info(),
+                        ],
+                        signer,
+                    )?;
+                }
+
+                // Revoke the listing delegate
+                let cpi_accounts = anchor_spl::token_interface::Revoke {
+                    source: ctx.accounts.seller_token_account.to_account_info(),
+                    authority: ctx.accounts.seller.to_account_info(),
+                };
+                
+                let cpi_program = ctx.accounts.token_program.to_account_info();
+                let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+                
+                anchor_spl::token_interface::revoke(cpi_ctx)?;
+            }
+            ListingMode::Compressed => {
+                return err!(NftMarketplaceError::UnsupportedListingMode);
+            }
+        }
+
         msg!("Listing cancelled for mint: {}", listing.mint);
         Ok(())
     }
 
-    // Buy an NFT
-    pub fn buy(ctx: Context<BuyNft>) -> Result<()> {
+    // Close an expired listing on the seller's behalf
+    //
+    // Anyone can call this once the listing has expired. Custody is returned as
+    // in `cancel_listing`, except that delegate listings are only thawed: the
+    // approval can only be revoked by the seller, and the listing delegate
+    // cannot act once the listing is closed. Compressed listings take the
+    // `leaf` and its proof as remaining accounts, and the listing delegate
+    // transfers the leaf back to the seller, which clears the delegation. The
+    // caller receives `LISTING_EXPIRY_REWARD` lamports out of the listing's
+    // rent.
+    pub fn expire_listing<'info>(
+        ctx: Context<'_, '_, '_, 'info, ExpireListing<'info>>,
+        leaf: Option<CompressedLeaf>,
+    ) -> Result<()> {
+        let listing = &ctx.accounts.listing;
+        let listing_key = listing.key();
+        require!(
+            listing.is_expired(Clock::get()?.unix_timestamp),
+            NftMarketplaceError::ListingNotExpired
+        );
+
+        match listing.mode {
+            ListingMode::Escrow => {
+                let vault = ctx
+                    .accounts
+                    .vault
+                    .as_ref()
+                    .ok_or(NftMarketplaceError::MissingListingAccount)?;
+                let seller_token_account = ctx
+                    .accounts
+                    .seller_token_account
+                    .as_ref()
+                    .ok_or(NftMarketplaceError::MissingListingAccount)?;
+                let mint = ctx
+                    .accounts
+                    .mint
+                    .as_ref()
+                    .ok_or(NftMarketplaceError::MissingListingAccount)?;
+
+                // Return the NFT from the listing vault to the seller
+                let seeds = &[
+                    b"vault".as_ref(),
+                    listing_key.as_ref(),
+                    &[listing.vault_bump],
+                ];
+                let signer = &[&seeds[..]];
+                
+                if is_programmable(&ctx.accounts.metadata)? {
+                    transfer_programmable_nft(
+                        ProgrammableTransfer {
+                            token: vault.to_account_info(),
+                            token_owner: vault.to_account_info(),
+                            destination: seller_token_account.to_account_info(),
+                            destination_owner: ctx.accounts.seller.to_account_info(),
+                            mint: mint.to_account_info(),
+                            metadata: ctx.accounts.metadata.to_account_info(),
+                            edition: required_account(&ctx.accounts.edition)?,
+                            owner_token_record: required_account(&ctx.accounts.owner_token_record)?,
+                            destination_token_record: required_account(&ctx.accounts.destination_token_record)?,
+                            authority: vault.to_account_info(),
+                            payer: ctx.accounts.cranker.to_account_info(),
+                            system_program: ctx.accounts.system_program.to_account_info(),
+                            sysvar_instructions: required_account(&ctx.accounts.sysvar_instructions)?,
+                            token_program: ctx.accounts.token_program.to_account_info(),
+                            associated_token_program: required_account(&ctx.accounts.associated_token_program)?,
+                            token_metadata_program: required_account(&ctx.accounts.token_metadata_program)?,
+                            authorization_rules_program: optional_account(&ctx.accounts.authorization_rules_program),
+                            authorization_rules: optional_account(&ctx.accounts.authorization_rules),
+                        },
+                        signer,
+                    )?;
+                } else {
+                    transfer_units(
+                        ctx.accounts.token_program.to_account_info(),
+                        vault.to_account_info(),
+                        mint,
+                        seller_token_account.to_account_info(),
+                        vault.to_account_info(),
+                        ctx.remaining_accounts,
+                        listing.quantity,
+                        signer,
+                    )?;
+                }
+
+                // Close the emptied vault, returning its rent to the seller
+                anchor_spl::token_interface::close_account(CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    anchor_spl::token_interface::CloseAccount {
+                        account: vault.to_account_info(),
+                        destination: ctx.accounts.seller.to_account_info(),
+                        authority: vault.to_account_info(),
+                    },
+                    signer,
+                ))?;
+            }
+            ListingMode::Delegate => {
+                // Thaw the NFT if it was frozen when listed
+                if listing.frozen {
+                    let delegate = ctx
+                        .accounts
+                        .delegate
+                        .as_ref()
+                        .ok_or(NftMarketplaceError::MissingListingAccount)?;
+                    let edition = ctx
+                        .accounts
+                        .edition
+                        .as_ref()
+                        .ok_or(NftMarketplaceError::MissingListingAccount)?;
+                    let token_metadata_program = ctx
+                        .accounts
+                        .token_metadata_program
+                        .as_ref()
+                        .ok_or(NftMarketplaceError::MissingListingAccount)?;
+                    let seller_token_account = ctx
+                        .accounts
+                        .seller_token_account
+                        .as_ref()
+                        .ok_or(NftMarketplaceError::MissingListingAccount)?;
+                    let mint = ctx
+                        .accounts
+                        .mint
+                        .as_ref()
+                        .ok_or(NftMarketplaceError::MissingListingAccount)?;
+
+                    let seeds = &[
+                        b"delegate".as_ref(),
+                        listing_key.as_ref(),
+                        &[listing.delegate_bump],
+                    ];
+                    let signer = &[&seeds[..]];
+
+                    invoke_signed(
+                        &mpl_token_metadata::instruction::thaw_delegated_account(
+                            mpl_token_metadata::ID,
+                            delegate.key(),
+                            seller_token_account.key(),
+                            edition.key(),
+                            mint.key(),
+                        ),
+                        &[
+                            delegate.to_account_info(),
+                            seller_token_account.to_account_info(),
+                            edition.to_account_info(),
+                            mint.to_account_info(),
+                            ctx.accounts.token_program.to_account_info(),
+                            token_metadata_program.to_account_info(),
+                        ],
+                        signer,
+                    )?;
+                }
+            }
+            ListingMode::Compressed => {
+                let leaf = leaf.ok_or(NftMarketplaceError::InvalidAssetId)?;
+                let delegate = listing_account(&ctx.accounts.delegate)?;
+                let merkle_tree = listing_account(&ctx.accounts.merkle_tree)?;
+                require_keys_eq!(
+                    listing.mint,
+                    mpl_bubblegum::utils::get_asset_id(&merkle_tree.key(), leaf.nonce),
+                    NftMarketplaceError::InvalidAssetId
+                );
+
+                let seeds = &[
+                    b"delegate".as_ref(),
+                    listing_key.as_ref(),
+                    &[listing.delegate_bump],
+                ];
+                let signer = &[&seeds[..]];
+
+                // Transferring the leaf to its owner resets the delegate to the owner
+                let proof = proof_accounts(ctx.remaining_accounts);
+                TransferCpi::new(
+                    &listing_account(&ctx.accounts.bubblegum_program)?,
+                    TransferCpiAccounts {
+                        tree_config: &listing_account(&ctx.accounts.tree_config)?,
+                        leaf_owner: (&ctx.accounts.seller, false),
+                        leaf_delegate: (&delegate, true),
+                        new_leaf_owner: &ctx.accounts.seller,
+                        merkle_tree: &merkle_tree,
+                        log_wrapper: &listing_account(&ctx.accounts.log_wrapper)?,
+                        compression_program: &listing_account(&ctx.accounts.compression_program)?,
+                        system_program: &ctx.accounts.system_program,
+                    },
+                    TransferInstructionArgs {
+                        root: leaf.root,
+                        data_hash: leaf.data_hash,
+                        creator_hash: leaf.creator_hash,
+                        nonce: leaf.nonce,
+                        index: leaf.index,
+                    },
+                )
+                .invoke_signed_with_remaining_accounts(signer, &proof)?;
+            }
+        }
+
+        // Reward the caller; the rest of the rent goes to the seller on close
+        Payer {
+            from: listing.to_account_info(),
+            authority: listing.to_account_info(),
+            payment_mint: None,
+            program: ctx.accounts.system_program.to_account_info(),
+            signer_seeds: &[],
+        }
+        .pay(ctx.accounts.cranker.to_account_info(), LISTING_EXPIRY_REWARD)?;
+
+        msg!("Expired listing closed for mint: {}", listing.mint);
+        Ok(())
+    }
+
+    // Buy `quantity` units of a listing
+    //
+    // The purchase fails if the current unit price, which decays over time
+    // for Dutch auction listings, exceeds `max_price`. The listing stays open
+    // until every unit is sold. Verified creators of the
+    // NFT must be passed as writable remaining accounts, in the order they
+    // appear in the metadata, followed by any extra accounts required by a
+    // Token-2022 transfer hook.
+    pub fn buy<'info>(
+        ctx: Context<'_, '_, '_, 'info, BuyNft<'info>>,
+        max_price: u64,
+        quantity: u64,
+    ) -> Result<()> {
         let listing = &ctx.accounts.listing;
         
-        // Verify the listing is active
+        // Verify the listing is active, unexpired, open to this buyer and within their limit
         require!(listing.active, NftMarketplaceError::InactiveListing);
+        let now = Clock::get()?.unix_timestamp;
+        require!(!listing.is_expired(now), NftMarketplaceError::ExpiredListing);
+        let price = listing.current_price(now);
+        require!(price <= max_price, NftMarketplaceError::PriceAboveMaximum);
+        if let Some(reserved_buyer) = listing.reserved_buyer {
+            require_keys_eq!(ctx.accounts.buyer.key(), reserved_buyer, NftMarketplaceError::NotReservedBuyer);
+        }
+        let (remaining, total_price) = listing.fill(quantity, price)?;
+        
+        // Apply the policy of the NFT's collection, if configured
+        let collection_config = collection_config(
+            &ctx.accounts.marketplace.key(),
+            &ctx.accounts.metadata,
+            ctx.accounts.collection_config.as_deref(),
+        )?;
+        require_collection_payment_mint(collection_config.as_ref(), listing.payment_mint)?;
         
         // Calculate fees
-        let marketplace = &ctx.accounts.marketplace;
-        let marketplace_fee_amount = (listing.price as u128)
-            .checked_mul(marketplace.fee as u128)
-            .unwrap()
-            .checked_div(10000)
-            .unwrap() as u64;
+        let royalties = creator_royalties(
+            &ctx.accounts.metadata,
+            &ctx.accounts.mint.to_account_info(),
+            total_price,
+            min_royalty(collection_config.as_ref()),
+        )?;
+        // The buyer pays the taker fee on top of the price, and the seller's
+        // maker fee or rebate is settled out of the proceeds
+        let (taker_fee_amount, maker_fee_amount) = trade_fees(
+            &ctx.accounts.marketplace,
+            collection_config.as_ref(),
+            total_price,
+        );
+        let seller_amount = sale_proceeds(total_price, maker_fee_amount, &royalties)?;
+        let marketplace_fee_amount = taker_fee_amount
+            .checked_add_signed(maker_fee_amount)
+            .ok_or(NftMarketplaceError::RebateExceedsTakerFee)?;
         
-        let seller_amount = listing.price.checked_sub(marketplace_fee_amount).unwrap();
+        let payment_mint = order_payment_mint(listing.payment_mint, &ctx.accounts.payment_mint)?;
+        let payer = Payer {
+            from: payment_account(
+                payment_mint,
+                ctx.accounts.buyer.to_account_info(),
+                &ctx.accounts.buyer_payment_account,
+            )?,
+            authority: ctx.accounts.buyer.to_account_info(),
+            payment_mint,
+            program: payment_account(
+                payment_mint,
+                ctx.accounts.system_program.to_account_info(),
+                &ctx.accounts.payment_token_program,
+            )?,
+            signer_seeds: &[],
+        };
+        
+        // Pay creator royalties
+        pay_royalties(&payer, &royalties, ctx.remaining_accounts)?;
         
         // Transfer payment to seller
-        anchor_lang::system_program::transfer(
-            CpiContext::new(
-                ctx.accounts.system_program.to_account_info(),
-                anchor_lang::system_program::Transfer {
-                    from: ctx.accounts.buyer.to_account_info(),
-                    to: ctx.accounts.seller.to_account_info(),
-                },
-            ),
+        payer.pay(
+            payment_account(
+                payment_mint,
+                ctx.accounts.seller.to_account_info(),
+                &ctx.accounts.seller_payment_account,
+            )?,
             seller_amount,
         )?;
         
-        // Transfer marketplace fee
-        anchor_lang::system_program::transfer(
-            CpiContext::new(
-                ctx.accounts.system_program.to_account_info(),
-                anchor_lang::system_program::Transfer {
-                    from: ctx.accounts.buyer.to_account_info(),
-                    to: ctx.accounts.treasury.to_account_info(),
-                },
-            ),
+        // Transfer marketplace fee, less the referrer's share
+        let referral_amount = pay_referral(
+            &payer,
+            &ctx.accounts.marketplace,
             marketplace_fee_amount,
+            &ctx.accounts.buyer.key(),
+            &ctx.accounts.referrer,
+            &ctx.accounts.registered_referrer,
+            &ctx.accounts.referrer_payment_account,
+        )?;
+        payer.pay(
+            payment_account(
+                payment_mint,
+                ctx.accounts.treasury.to_account_info(),
+                &ctx.accounts.treasury_payment_account,
+            )?,
+            marketplace_fee_amount - referral_amount,
         )?;
         
         // Transfer NFT to buyer
-        let seeds = &[
-            b"marketplace".as_ref(),
-            &[marketplace.bump],
-        ];
-        let signer = &[&seeds[..]];
+        let listing_key = listing.key();
+        match listing.mode {
+            ListingMode::Escrow => {
+                let vault = ctx
+                    .accounts
+                    .vault
+                    .as_ref()
+                    .ok_or(NftMarketplaceError::MissingListingAccount)?;
+
+                let seeds = &[
+                    b"vault".as_ref(),
+                    listing_key.as_ref(),
+                    &[listing.vault_bump],
+                ];
+                let signer = &[&seeds[..]];
+                
+                if is_programmable(&ctx.accounts.metadata)? {
+                    transfer_programmable_nft(
+                        ProgrammableTransfer {
+                            token: vault.to_account_info(),
+                            token_owner: vault.to_account_info(),
+                            destination: ctx.accounts.buyer_token_account.to_account_info(),
+                            destination_owner: ctx.accounts.buyer.to_account_info(),
+                            mint: ctx.accounts.mint.to_account_info(),
+                            metadata: ctx.accounts.metadata.to_account_info(),
+                            edition: required_account(&ctx.accounts.edition)?,
+                            owner_token_record: required_account(&ctx.accounts.owner_token_record)?,
+                            destination_token_record: required_account(&ctx.accounts.destination_token_record)?,
+                            authority: vault.to_account_info(),
+                            payer: ctx.accounts.buyer.to_account_info(),
+                            system_program: ctx.accounts.system_program.to_account_info(),
+                            sysvar_instructions: required_account(&ctx.accounts.sysvar_instructions)?,
+                            token_program: ctx.accounts.token_program.to_account_info(),
+                            associated_token_program: ctx.accounts.associated_token_program.to_account_info(),
+                            token_metadata_program: required_account(&ctx.accounts.token_metadata_program)?,
+                            authorization_rules_program: optional_account(&ctx.accounts.authorization_rules_program),
+                            authorization_rules: optional_account(&ctx.accounts.authorization_rules),
+                        },
+                        signer,
+                    )?;
+                } else {
+                    transfer_units(
+                        ctx.accounts.token_program.to_account_info(),
+                        vault.to_account_info(),
+                        &ctx.accounts.mint,
+                        ctx.accounts.buyer_token_account.to_account_info(),
+                        vault.to_account_info(),
+                        &ctx.remaining_accounts[royalties.len()..],
+                        quantity,
+                        signer,
+                    )?;
+                }
+
+                // Close the vault once emptied, returning its rent to the seller
+                if remaining == 0 {
+                    anchor_spl::token_interface::close_account(CpiContext::new_with_signer(
+                        ctx.accounts.token_program.to_account_info(),
+                        anchor_spl::token_interface::CloseAccount {
+                            account: vault.to_account_info(),
+                            destination: ctx.accounts.seller.to_account_info(),
+                            authority: vault.to_account_info(),
+                        },
+                        signer,
+                    ))?;
+                }
+            }
+            ListingMode::Delegate => {
+                let seller_token_account = ctx
+                    .accounts
+                    .seller_token_account
+                    .as_ref()
+                    .ok_or(NftMarketplaceError::MissingListingAccount)?;
+                let delegate = ctx
+                    .accounts
+                    .delegate
+                    .as_ref()
+                    .ok_or(NftMarketplaceError::MissingListingAccount)?;
+
+                let seeds = &[
+                    b"delegate".as_ref(),
+                    listing_key.as_ref(),
+                    &[listing.delegate_bump],
+                ];
+                let signer = &[&seeds[..]];
+
+                // Thaw the NFT if it was frozen when listed
+                if listing.frozen {
+                    let edition = ctx
+                        .accounts
+                        .edition
+                        .as_ref()
+                        .ok_or(NftMarketplaceError::MissingListingAccount)?;
+                    let token_metadata_program = ctx
+                        .accounts
+                        .token_metadata_program
+                        .as_ref()
+                        .ok_or(NftMarketplaceError::MissingListingAccount)?;
+
+                    invoke_signed(
+                        &mpl_token_metadata::instruction::thaw_delegated_account(
+                            mpl_token_metadata::ID,
+                            delegate.key(),
+                            seller_token_account.key(),
+                            edition.key(),
+                            ctx.accounts.mint.key(),
+                        ),
+                        &[
+                            delegate.to_account_info(),
+                            seller_token_account.to_account_info(),
+                            edition.to_account_info(),
+                            ctx.accounts.mint.to_account_info(),
+                            ctx.accounts.token_program.to_account_info(),
+                            token_metadata_program.to_account_info(),
+                        ],
+                        signer,
+                    )?;
+                }
+
+                transfer_units(
+                    ctx.accounts.token_program.to_account_info(),
+                    seller_token_account.to_account_info(),
+                    &ctx.accounts.mint,
+                    ctx.accounts.buyer_token_account.to_account_info(),
+                    delegate.to_account_info(),
+                    &ctx.remaining_accounts[royalties.len()..],
+                    quantity,
+                    signer,
+                )?;
+            }
+            ListingMode::Compressed => {
+                return err!(NftMarketplaceError::UnsupportedListingMode);
+            }
+        }
         
-        let cpi_accounts = anchor_spl::token::Transfer {
-            from: ctx.accounts.seller_token_account.to_account_info(),
-            to: ctx.accounts.buyer_token_account.to_account_info(),
-            authority: ctx.accounts.seller.to_account_info(),
-        };
+        // Decrement the remaining quantity, closing the listing once it is sold out
+        let listing = &mut ctx.accounts.listing;
+        listing.quantity = remaining;
+        if remaining == 0 {
+            listing.close(ctx.accounts.seller.to_account_info())?;
+        }
         
-        let cpi_program = ctx.accounts.token_program.to_account_info();
-        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+        msg!("{} units purchased for {}, {} remaining", quantity, total_price, remaining);
+        Ok(())
+    }
+
+    // List a compressed NFT for sale
+    //
+    // The leaf is delegated to a listing PDA through Bubblegum, which verifies
+    // the merkle proof passed as remaining accounts. `metadata` must hash to
+    // the leaf's data and creator hashes. If `reserved_buyer` is set, only
+    // that wallet can buy the NFT. The listing can no longer be bought after
+    // `expiry_time`.
+    pub fn list_compressed<'info>(
+        ctx: Context<'_, '_, '_, 'info, ListCompressedNft<'info>>,
+        price: u64,
+        asset_id: Pubkey,
+        leaf: CompressedLeaf,
+        metadata: MetadataArgs,
+        reserved_buyer: Option<Pubkey>,
+        expiry_time: i64,
+    ) -> Result<()> {
+        require_keys_eq!(
+            asset_id,
+            mpl_bubblegum::utils::get_asset_id(&ctx.accounts.merkle_tree.key(), leaf.nonce),
+            NftMarketplaceError::InvalidAssetId
+        );
+        require_compressed_metadata(&leaf, &metadata)?;
+        let now = Clock::get()?.unix_timestamp;
+        require!(expiry_time > now, NftMarketplaceError::InvalidExpiry);
+        let payment_mint = accepted_payment_mint(&ctx.accounts.marketplace, &ctx.accounts.payment_mint)?;
         
-        anchor_spl::token::transfer(cpi_ctx, 1)?;
+        // Apply the policy of the NFT's collection, if configured
+        let collection_config = compressed_collection_config(
+            &ctx.accounts.marketplace.key(),
+            &metadata,
+            ctx.accounts.collection_config.as_deref(),
+        )?;
+        require_collection_payment_mint(collection_config.as_ref(), payment_mint)?;
+        require_collection_min_price(collection_config.as_ref(), price)?;
         
-        // Update listing status
         let listing = &mut ctx.accounts.listing;
-        listing.active = false;
+        listing.marketplace = ctx.accounts.marketplace.key();
+        listing.seller = ctx.accounts.seller.key();
+        listing.mint = asset_id;
+        listing.price = price;
+        listing.active = true;
+        listing.created_at = now;
+        listing.bump = ctx.bumps.listing;
+        listing.quantity = 1;
+        listing.expires_at = expiry_time;
+        listing.mode = ListingMode::Compressed;
+        listing.delegate_bump = ctx.bumps.delegate;
+        listing.payment_mint = payment_mint;
+        listing.reserved_buyer = reserved_buyer;
+        
+        // Delegate the leaf to the listing delegate
+        let proof = proof_accounts(ctx.remaining_accounts);
+        DelegateCpi::new(
+            &ctx.accounts.bubblegum_program,
+            DelegateCpiAccounts {
+                tree_config: &ctx.accounts.tree_config,
+                leaf_owner: &ctx.accounts.seller,
+                previous_leaf_delegate: &ctx.accounts.previous_leaf_delegate,
+                new_leaf_delegate: &ctx.accounts.delegate,
+                merkle_tree: &ctx.accounts.merkle_tree,
+                log_wrapper: &ctx.accounts.log_wrapper,
+                compression_program: &ctx.accounts.compression_program,
+                system_program: &ctx.accounts.system_program,
+            },
+            DelegateInstructionArgs {
+                root: leaf.root,
+                data_hash: leaf.data_hash,
+                creator_hash: leaf.creator_hash,
+                nonce: leaf.nonce,
+                index: leaf.index,
+            },
+        )
+        .invoke_with_remaining_accounts(&proof)?;
+        
+        msg!("Compressed NFT listed for sale at price: {}", price);
+        Ok(())
+    }
+
+    // Cancel a compressed NFT listing, handing the leaf delegate back to the seller
+    pub fn cancel_compressed_listing<'info>(
+        ctx: Context<'_, '_, '_, 'info, CancelCompressedListing<'info>>,
+        leaf: CompressedLeaf,
+    ) -> Result<()> {
+        let listing = &ctx.accounts.listing;
+        require_keys_eq!(
+            listing.mint,
+            mpl_bubblegum::utils::get_asset_id(&ctx.accounts.merkle_tree.key(), leaf.nonce),
+            NftMarketplaceError::InvalidAssetId
+        );
+        
+        let proof = proof_accounts(ctx.remaining_accounts);
+        DelegateCpi::new(
+            &ctx.accounts.bubblegum_program,
+            DelegateCpiAccounts {
+                tree_config: &ctx.accounts.tree_config,
+                leaf_owner: &ctx.accounts.seller,
+                previous_leaf_delegate: &ctx.accounts.delegate,
+                new_leaf_delegate: &ctx.accounts.seller,
+                merkle_tree: &ctx.accounts.merkle_tree,
+                log_wrapper: &ctx.accounts.log_wrapper,
+                compression_program: &ctx.accounts.compression_program,
+                system_program: &ctx.accounts.system_program,
+            },
+            DelegateInstructionArgs {
+                root: leaf.root,
+                data_hash: leaf.data_hash,
+                creator_hash: leaf.creator_hash,
+                nonce: leaf.nonce,
+                index: leaf.index,
+            },
+        )
+        .invoke_with_remaining_accounts(&proof)?;
+        
+        msg!("Compressed listing cancelled for asset: {}", listing.mint);
+        Ok(())
+    }
+
+    // Buy a compressed NFT
+    //
+    // The purchase fails if the listing price exceeds `max_price`, so a
+    // repricing cannot front-run the buyer. `metadata` must hash to the leaf's data and creator hashes. Verified
+    // creators of the NFT must be passed as writable remaining accounts, in
+    // the order they appear in the metadata, followed by the proof nodes.
+    pub fn buy_compressed<'info>(
+        ctx: Context<'_, '_, '_, 'info, BuyCompressedNft<'info>>,
+        max_price: u64,
+        leaf: CompressedLeaf,
+        metadata: MetadataArgs,
+    ) -> Result<()> {
+        let listing = &ctx.accounts.listing;
+        
+        // Verify the listing is active, unexpired, within the buyer's limit,
+        // open to them and matches the leaf being bought
+        require!(listing.active, NftMarketplaceError::InactiveListing);
+        require!(
+            !listing.is_expired(Clock::get()?.unix_timestamp),
+            NftMarketplaceError::ExpiredListing
+        );
+        require!(listing.price <= max_price, NftMarketplaceError::PriceAboveMaximum);
+        if let Some(reserved_buyer) = listing.reserved_buyer {
+            require_keys_eq!(ctx.accounts.buyer.key(), reserved_buyer, NftMarketplaceError::NotReservedBuyer);
+        }
+        require_keys_eq!(
+            listing.mint,
+            mpl_bubblegum::utils::get_asset_id(&ctx.accounts.merkle_tree.key(), leaf.nonce),
+            NftMarketplaceError::InvalidAssetId
+        );
+        require_compressed_metadata(&leaf, &metadata)?;
+        
+        // Apply the policy of the NFT's collection, if configured
+        let collection_config = compressed_collection_config(
+            &ctx.accounts.marketplace.key(),
+            &metadata,
+            ctx.accounts.collection_config.as_deref(),
+        )?;
+        require_collection_payment_mint(collection_config.as_ref(), listing.payment_mint)?;
+        // Repricing a compressed listing can't see its collection, so the
+        // minimum price is enforced here as well
+        require_collection_min_price(collection_config.as_ref(), listing.price)?;
+        
+        // Calculate fees
+        let royalties = compressed_royalties(&metadata, listing.price, min_royalty(collection_config.as_ref()));
+        // The buyer pays the taker fee on top of the price, and the seller's
+        // maker fee or rebate is settled out of the proceeds
+        let (taker_fee_amount, maker_fee_amount) = trade_fees(
+            &ctx.accounts.marketplace,
+            collection_config.as_ref(),
+            listing.price,
+        );
+        let seller_amount = sale_proceeds(listing.price, maker_fee_amount, &royalties)?;
+        let marketplace_fee_amount = taker_fee_amount
+            .checked_add_signed(maker_fee_amount)
+            .ok_or(NftMarketplaceError::RebateExceedsTakerFee)?;
+        
+        let payment_mint = order_payment_mint(listing.payment_mint, &ctx.accounts.payment_mint)?;
+        let payer = Payer {
+            from: payment_account(
+                payment_mint,
+                ctx.accounts.buyer.to_account_info(),
+                &ctx.accounts.buyer_payment_account,
+            )?,
+            authority: ctx.accounts.buyer.to_account_info(),
+            payment_mint,
+            program: payment_account(
+                payment_mint,
+                ctx.accounts.system_program.to_account_info(),
+                &ctx.accounts.payment_token_program,
+            )?,
+            signer_seeds: &[],
+        };
+        
+        // Pay creator royalties from the accounts ahead of the proof nodes
+        let (creator_accounts, proof_nodes) = ctx
+            .remaining_accounts
+            .split_at(royalties.len().min(ctx.remaining_accounts.len()));
+        pay_royalties(&payer, &royalties, creator_accounts)?;
+        
+        // Transfer payment to seller
+        payer.pay(
+            payment_account(
+                payment_mint,
+                ctx.accounts.seller.to_account_info(),
+                &ctx.accounts.seller_payment_account,
+            )?,
+            seller_amount,
+        )?;
+        
+        // Transfer marketplace fee, less the referrer's share
+        let referral_amount = pay_referral(
+            &payer,
+            &ctx.accounts.marketplace,
+            marketplace_fee_amount,
+            &ctx.accounts.buyer.key(),
+            &ctx.accounts.referrer,
+            &ctx.accounts.registered_referrer,
+            &ctx.accounts.referrer_payment_account,
+        )?;
+        payer.pay(
+            payment_account(
+                payment_mint,
+                ctx.accounts.treasury.to_account_info(),
+                &ctx.accounts.treasury_payment_account,
+            )?,
+            marketplace_fee_amount - referral_amount,
+        )?;
+        
+        // Transfer the leaf to the buyer, signing as the listing delegate
+        let listing_key = listing.key();
+        let seeds = &[
+            b"delegate".as_ref(),
+            listing_key.as_ref(),
+            &[listing.delegate_bump],
+        ];
+        let signer = &[&seeds[..]];
         
-        msg!("NFT purchased for {} SOL", listing.price);
+        let proof = proof_accounts(proof_nodes);
+        TransferCpi::new(
+            &ctx.accounts.bubblegum_program,
+            TransferCpiAccounts {
+                tree_config: &ctx.accounts.tree_config,
+                leaf_owner: (&ctx.accounts.seller, false),
+                leaf_delegate: (&ctx.accounts.delegate, true),
+                new_leaf_owner: &ctx.accounts.buyer,
+                merkle_tree: &ctx.accounts.merkle_tree,
+                log_wrapper: &ctx.accounts.log_wrapper,
+                compression_program: &ctx.accounts.compression_program,
+                system_program: &ctx.accounts.system_program,
+            },
+            TransferInstructionArgs {
+                root: leaf.root,
+                data_hash: leaf.data_hash,
+                creator_hash: leaf.creator_hash,
+                nonce: leaf.nonce,
+                index: leaf.index,
+            },
+        )
+        .invoke_signed_with_remaining_accounts(signer, &proof)?;
+        
+        msg!("Compressed NFT purchased for {}", listing.price);
         Ok(())
     }
 
@@ -150,25 +1335,53 @@ pub mod nft_marketplace {
         price: u64,
         expiry_time: i64,
     ) -> Result<()> {
+        require_transferable(&ctx.accounts.mint)?;
+        
         let offer = &mut ctx.accounts.offer;
+        offer.marketplace = ctx.accounts.marketplace.key();
         offer.buyer = ctx.accounts.buyer.key();
         offer.mint = ctx.accounts.mint.key();
         offer.price = price;
         offer.created_at = Clock::get()?.unix_timestamp;
         offer.expires_at = expiry_time;
         offer.active = true;
-        offer.bump = *ctx.bumps.get("offer").unwrap();
+        offer.bump = ctx.bumps.offer;
+        offer.escrow_bump = ctx.bumps.escrow;
+        offer.payment_mint = accepted_payment_mint(&ctx.accounts.marketplace, &ctx.accounts.payment_mint)?;
         
-        // Escrow payment
-        anchor_lang::system_program::transfer(
-            CpiContext::new(
+        // Apply the policy of the NFT's collection, if configured
+        let collection_config = collection_config(
+            &ctx.accounts.marketplace.key(),
+            &ctx.accounts.metadata,
+            ctx.accounts.collection_config.as_deref(),
+        )?;
+        require_collection_payment_mint(collection_config.as_ref(), offer.payment_mint)?;
+        
+        // Lock in the maker fee, escrowed on top of the price or deducted from it
+        // as a rebate
+        let (_, maker_fee_amount) = trade_fees(&ctx.accounts.marketplace, collection_config.as_ref(), price);
+        offer.maker_fee = maker_fee_amount;
+        
+        // Escrow payment, in lamports or in tokens held by the payment escrow
+        let payment_mint = ctx.accounts.payment_mint.as_ref();
+        Payer {
+            from: payment_account(
+                payment_mint,
+                ctx.accounts.buyer.to_account_info(),
+                &ctx.accounts.buyer_payment_account,
+            )?,
+            authority: ctx.accounts.buyer.to_account_info(),
+            payment_mint,
+            program: payment_account(
+                payment_mint,
                 ctx.accounts.system_program.to_account_info(),
-                anchor_lang::system_program::Transfer {
-                    from: ctx.accounts.buyer.to_account_info(),
-                    to: ctx.accounts.escrow.to_account_info(),
-                },
-            ),
-            price,
+                &ctx.accounts.payment_token_program,
+            )?,
+            signer_seeds: &[],
+        }
+        .pay(
+            order_escrow(payment_mint, &ctx.accounts.escrow, &ctx.accounts.payment_escrow)?,
+            offer.escrowed_amount()?,
         )?;
         
         msg!("Offer made for NFT at price: {}", price);
@@ -176,7 +1389,11 @@ pub mod nft_marketplace {
     }
 
     // Accept an offer
-    pub fn accept_offer(ctx: Context<AcceptOffer>) -> Result<()> {
+    //
+    // Verified creators of the NFT must be passed as writable remaining
+    // accounts, in the order they appear in the metadata, followed by any
+    // extra accounts required by a Token-2022 transfer hook.
+    pub fn accept_offer<'info>(ctx: Context<'_, '_, '_, 'info, AcceptOffer<'info>>) -> Result<()> {
         let offer = &ctx.accounts.offer;
         
         // Verify the offer is active and not expired
@@ -186,65 +1403,124 @@ pub mod nft_marketplace {
             NftMarketplaceError::ExpiredOffer
         );
         
-        // Calculate fees
-        let marketplace = &ctx.accounts.marketplace;
-        let marketplace_fee_amount = (offer.price as u128)
-            .checked_mul(marketplace.fee as u128)
-            .unwrap()
-            .checked_div(10000)
-            .unwrap() as u64;
+        // Apply the policy of the NFT's collection, if configured
+        let collection_config = collection_config(
+            &ctx.accounts.marketplace.key(),
+            &ctx.accounts.metadata,
+            ctx.accounts.collection_config.as_deref(),
+        )?;
+        require_collection_payment_mint(collection_config.as_ref(), offer.payment_mint)?;
         
-        let seller_amount = offer.price.checked_sub(marketplace_fee_amount).unwrap();
+        // Calculate fees
+        let royalties = creator_royalties(
+            &ctx.accounts.metadata,
+            &ctx.accounts.mint.to_account_info(),
+            offer.price,
+            min_royalty(collection_config.as_ref()),
+        )?;
+        // The seller pays the taker fee out of the proceeds. The escrow holds the
+        // maker fee locked in by the buyer, or lacks its rebate. A rebate
+        // above the current taker fee cannot be funded, so the fill is rejected.
+        let (taker_fee_amount, _) = trade_fees(&ctx.accounts.marketplace, collection_config.as_ref(), offer.price);
+        let marketplace_fee_amount = taker_fee_amount
+            .checked_add_signed(offer.maker_fee)
+            .ok_or(NftMarketplaceError::RebateExceedsTakerFee)?;
+        let seller_amount = sale_proceeds(offer.escrowed_amount()?, marketplace_fee_amount as i64, &royalties)?;
         
-        // Transfer payment from escrow to seller
         let seeds = &[
-            b"escrow".as_ref(),
+            b"offer".as_ref(),
+            offer.marketplace.as_ref(),
             offer.mint.as_ref(),
             offer.buyer.as_ref(),
             &[offer.bump],
         ];
         let signer = &[&seeds[..]];
         
-        anchor_lang::system_program::transfer(
-            CpiContext::new_with_signer(
+        // Lamports are held by the escrow PDA, tokens by a payment escrow owned by the offer
+        let payment_mint = order_payment_mint(offer.payment_mint, &ctx.accounts.payment_mint)?;
+        let payer = Payer {
+            from: order_escrow(payment_mint, &ctx.accounts.escrow, &ctx.accounts.payment_escrow)?,
+            authority: offer.to_account_info(),
+            payment_mint,
+            program: payment_account(
+                payment_mint,
                 ctx.accounts.system_program.to_account_info(),
-                anchor_lang::system_program::Transfer {
-                    from: ctx.accounts.escrow.to_account_info(),
-                    to: ctx.accounts.seller.to_account_info(),
-                },
-                signer,
-            ),
+                &ctx.accounts.payment_token_program,
+            )?,
+            signer_seeds: signer,
+        };
+        
+        // Pay creator royalties from escrow
+        pay_royalties(&payer, &royalties, ctx.remaining_accounts)?;
+        
+        // Transfer payment from escrow to seller
+        payer.pay(
+            payment_account(
+                payer.payment_mint,
+                ctx.accounts.seller.to_account_info(),
+                &ctx.accounts.seller_payment_account,
+            )?,
             seller_amount,
         )?;
         
-        // Transfer marketplace fee
-        anchor_lang::system_program::transfer(
-            CpiContext::new_with_signer(
-                ctx.accounts.system_program.to_account_info(),
-                anchor_lang::system_program::Transfer {
-                    from: ctx.accounts.escrow.to_account_info(),
-                    to: ctx.accounts.treasury.to_account_info(),
-                },
-                signer,
-            ),
+        // Transfer marketplace fee, less the referrer's share
+        let referral_amount = pay_referral(
+            &payer,
+            &ctx.accounts.marketplace,
             marketplace_fee_amount,
+            &ctx.accounts.seller.key(),
+            &ctx.accounts.referrer,
+            &ctx.accounts.registered_referrer,
+            &ctx.accounts.referrer_payment_account,
+        )?;
+        payer.pay(
+            payment_account(
+                payer.payment_mint,
+                ctx.accounts.treasury.to_account_info(),
+                &ctx.accounts.treasury_payment_account,
+            )?,
+            marketplace_fee_amount - referral_amount,
         )?;
         
-        // Transfer NFT to buyer
-        let cpi_accounts = anchor_spl::token::Transfer {
-            from: ctx.accounts.seller_token_account.to_account_info(),
-            to: ctx.accounts.buyer_token_account.to_account_info(),
-            authority: ctx.accounts.seller.to_account_info(),
-        };
-        
-        let cpi_program = ctx.accounts.token_program.to_account_info();
-        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
-        
-        anchor_spl::token::transfer(cpi_ctx, 1)?;
+        // Close the drained escrow, returning its rent to the buyer
+        payer.close(ctx.accounts.buyer.to_account_info())?;
         
-        // Update offer status
-        let offer = &mut ctx.accounts.offer;
-        offer.active = false;
+        // Transfer NFT to buyer
+        if is_programmable(&ctx.accounts.metadata)? {
+            transfer_programmable_nft(
+                ProgrammableTransfer {
+                    token: ctx.accounts.seller_token_account.to_account_info(),
+                    token_owner: ctx.accounts.seller.to_account_info(),
+                    destination: ctx.accounts.buyer_token_account.to_account_info(),
+                    destination_owner: ctx.accounts.buyer.to_account_info(),
+                    mint: ctx.accounts.mint.to_account_info(),
+                    metadata: ctx.accounts.metadata.to_account_info(),
+                    edition: required_account(&ctx.accounts.edition)?,
+                    owner_token_record: required_account(&ctx.accounts.owner_token_record)?,
+                    destination_token_record: required_account(&ctx.accounts.destination_token_record)?,
+                    authority: ctx.accounts.seller.to_account_info(),
+                    payer: ctx.accounts.seller.to_account_info(),
+                    system_program: ctx.accounts.system_program.to_account_info(),
+                    sysvar_instructions: required_account(&ctx.accounts.sysvar_instructions)?,
+                    token_program: ctx.accounts.token_program.to_account_info(),
+                    associated_token_program: required_account(&ctx.accounts.associated_token_program)?,
+                    token_metadata_program: required_account(&ctx.accounts.token_metadata_program)?,
+                    authorization_rules_program: optional_account(&ctx.accounts.authorization_rules_program),
+                    authorization_rules: optional_account(&ctx.accounts.authorization_rules),
+                },
+                &[],
+            )?;
+        } else {
+            transfer_nft(
+                ctx.accounts.token_program.to_account_info(),
+                ctx.accounts.seller_token_account.to_account_info(),
+                &ctx.accounts.mint,
+                ctx.accounts.buyer_token_account.to_account_info(),
+                ctx.accounts.seller.to_account_info(),
+                &ctx.remaining_accounts[royalties.len()..],
+                &[],
+            )?;
+        }
         
         msg!("Offer accepted for NFT at price: {}", offer.price);
         Ok(())
@@ -252,34 +1528,42 @@ pub mod nft_marketplace {
 
     // Cancel an offer
     pub fn cancel_offer(ctx: Context<CancelOffer>) -> Result<()> {
-        let offer = &mut ctx.accounts.offer;
+        let offer = &ctx.accounts.offer;
         
         // Verify the offer is active
         require!(offer.active, NftMarketplaceError::InactiveOffer);
         
         // Return funds to buyer
         let seeds = &[
-            b"escrow".as_ref(),
+            b"offer".as_ref(),
+            offer.marketplace.as_ref(),
             offer.mint.as_ref(),
             offer.buyer.as_ref(),
             &[offer.bump],
         ];
         let signer = &[&seeds[..]];
         
-        anchor_lang::system_program::transfer(
-            CpiContext::new_with_signer(
+        let payment_mint = order_payment_mint(offer.payment_mint, &ctx.accounts.payment_mint)?;
+        let payer = Payer {
+            from: order_escrow(payment_mint, &ctx.accounts.escrow, &ctx.accounts.payment_escrow)?,
+            authority: offer.to_account_info(),
+            payment_mint,
+            program: payment_account(
+                payment_mint,
                 ctx.accounts.system_program.to_account_info(),
-                anchor_lang::system_program::Transfer {
-                    from: ctx.accounts.escrow.to_account_info(),
-                    to: ctx.accounts.buyer.to_account_info(),
-                },
-                signer,
-            ),
-            offer.price,
+                &ctx.accounts.payment_token_program,
+            )?,
+            signer_seeds: signer,
+        };
+        payer.pay(
+            payment_account(
+                payment_mint,
+                ctx.accounts.buyer.to_account_info(),
+                &ctx.accounts.buyer_payment_account,
+            )?,
+            offer.escrowed_amount()?,
         )?;
-        
-        // Update offer status
-        offer.active = false;
+        payer.close(ctx.accounts.buyer.to_account_info())?;
         
         msg!("Offer cancelled for NFT");
         Ok(())
@@ -291,25 +1575,53 @@ pub mod nft_marketplace {
         price: u64,
         expiry_time: i64,
     ) -> Result<()> {
+        require_transferable(&ctx.accounts.mint)?;
+        
         let bid = &mut ctx.accounts.bid;
+        bid.marketplace = ctx.accounts.marketplace.key();
         bid.bidder = ctx.accounts.bidder.key();
         bid.mint = ctx.accounts.mint.key();
         bid.price = price;
         bid.created_at = Clock::get()?.unix_timestamp;
         bid.expires_at = expiry_time;
         bid.active = true;
-        bid.bump = *ctx.bumps.get("bid").unwrap();
+        bid.bump = ctx.bumps.bid;
+        bid.escrow_bump = ctx.bumps.escrow;
+        bid.payment_mint = accepted_payment_mint(&ctx.accounts.marketplace, &ctx.accounts.payment_mint)?;
         
-        // Escrow payment
-        anchor_lang::system_program::transfer(
-            CpiContext::new(
-                ctx.accounts.system_program.to_account_info(),
-                anchor_lang::system_program::Transfer {
-                    from: ctx.accounts.bidder.to_account_info(),
-                    to: ctx.accounts.escrow.to_account_info(),
-                },
-            ),
-            price,
+        // Apply the policy of the NFT's collection, if configured
+        let collection_config = collection_config(
+            &ctx.accounts.marketplace.key(),
+            &ctx.accounts.metadata,
+            ctx.accounts.collection_config.as_deref(),
+        )?;
+        require_collection_payment_mint(collection_config.as_ref(), bid.payment_mint)?;
+        
+        // Lock in the maker fee, escrowed on top of the price or deducted from it
+        // as a rebate
+        let (_, maker_fee_amount) = trade_fees(&ctx.accounts.marketplace, collection_config.as_ref(), price);
+        bid.maker_fee = maker_fee_amount;
+        
+        // Escrow payment, in lamports or in tokens held by the payment escrow
+        let payment_mint = ctx.accounts.payment_mint.as_ref();
+        Payer {
+            from: payment_account(
+                payment_mint,
+                ctx.accounts.bidder.to_account_info(),
+                &ctx.accounts.bidder_payment_account,
+            )?,
+            authority: ctx.accounts.bidder.to_account_info(),
+            payment_mint,
+            program: payment_account(
+                payment_mint,
+                ctx.accounts.system_program.to_account_info(),
+                &ctx.accounts.payment_token_program,
+            )?,
+            signer_seeds: &[],
+        }
+        .pay(
+            order_escrow(payment_mint, &ctx.accounts.escrow, &ctx.accounts.payment_escrow)?,
+            bid.escrowed_amount()?,
         )?;
         
         // Emit event
@@ -317,6 +1629,7 @@ pub mod nft_marketplace {
             bidder: bid.bidder,
             mint: bid.mint,
             price: bid.price,
+            payment_mint: bid.payment_mint,
             expires_at: bid.expires_at,
         });
         
@@ -325,7 +1638,11 @@ pub mod nft_marketplace {
     }
     
     // Accept a bid
-    pub fn accept_bid(ctx: Context<AcceptBid>) -> Result<()> {
+    //
+    // Verified creators of the NFT must be passed as writable remaining
+    // accounts, in the order they appear in the metadata, followed by any
+    // extra accounts required by a Token-2022 transfer hook.
+    pub fn accept_bid<'info>(ctx: Context<'_, '_, '_, 'info, AcceptBid<'info>>) -> Result<()> {
         let bid = &ctx.accounts.bid;
         
         // Verify the bid is active and not expired
@@ -335,65 +1652,124 @@ pub mod nft_marketplace {
             NftMarketplaceError::ExpiredBid
         );
         
-        // Calculate fees
-        let marketplace = &ctx.accounts.marketplace;
-        let marketplace_fee_amount = (bid.price as u128)
-            .checked_mul(marketplace.fee as u128)
-            .unwrap()
-            .checked_div(10000)
-            .unwrap() as u64;
+        // Apply the policy of the NFT's collection, if configured
+        let collection_config = collection_config(
+            &ctx.accounts.marketplace.key(),
+            &ctx.accounts.metadata,
+            ctx.accounts.collection_config.as_deref(),
+        )?;
+        require_collection_payment_mint(collection_config.as_ref(), bid.payment_mint)?;
         
-        let seller_amount = bid.price.checked_sub(marketplace_fee_amount).unwrap();
+        // Calculate fees
+        let royalties = creator_royalties(
+            &ctx.accounts.metadata,
+            &ctx.accounts.mint.to_account_info(),
+            bid.price,
+            min_royalty(collection_config.as_ref()),
+        )?;
+        // The seller pays the taker fee out of the proceeds. The escrow holds the
+        // maker fee locked in by the bidder, or lacks its rebate. A rebate
+        // above the current taker fee cannot be funded, so the fill is rejected.
+        let (taker_fee_amount, _) = trade_fees(&ctx.accounts.marketplace, collection_config.as_ref(), bid.price);
+        let marketplace_fee_amount = taker_fee_amount
+            .checked_add_signed(bid.maker_fee)
+            .ok_or(NftMarketplaceError::RebateExceedsTakerFee)?;
+        let seller_amount = sale_proceeds(bid.escrowed_amount()?, marketplace_fee_amount as i64, &royalties)?;
         
-        // Transfer payment from escrow to seller
         let seeds = &[
-            b"bid_escrow".as_ref(),
+            b"bid".as_ref(),
+            bid.marketplace.as_ref(),
             bid.mint.as_ref(),
             bid.bidder.as_ref(),
             &[bid.bump],
         ];
         let signer = &[&seeds[..]];
         
-        anchor_lang::system_program::transfer(
-            CpiContext::new_with_signer(
+        // Lamports are held by the escrow PDA, tokens by a payment escrow owned by the bid
+        let payment_mint = order_payment_mint(bid.payment_mint, &ctx.accounts.payment_mint)?;
+        let payer = Payer {
+            from: order_escrow(payment_mint, &ctx.accounts.escrow, &ctx.accounts.payment_escrow)?,
+            authority: bid.to_account_info(),
+            payment_mint,
+            program: payment_account(
+                payment_mint,
                 ctx.accounts.system_program.to_account_info(),
-                anchor_lang::system_program::Transfer {
-                    from: ctx.accounts.escrow.to_account_info(),
-                    to: ctx.accounts.seller.to_account_info(),
-                },
-                signer,
-            ),
+                &ctx.accounts.payment_token_program,
+            )?,
+            signer_seeds: signer,
+        };
+        
+        // Pay creator royalties from escrow
+        pay_royalties(&payer, &royalties, ctx.remaining_accounts)?;
+        
+        // Transfer payment from escrow to seller
+        payer.pay(
+            payment_account(
+                payer.payment_mint,
+                ctx.accounts.seller.to_account_info(),
+                &ctx.accounts.seller_payment_account,
+            )?,
             seller_amount,
         )?;
         
-        // Transfer marketplace fee
-        anchor_lang::system_program::transfer(
-            CpiContext::new_with_signer(
-                ctx.accounts.system_program.to_account_info(),
-                anchor_lang::system_program::Transfer {
-                    from: ctx.accounts.escrow.to_account_info(),
-                    to: ctx.accounts.treasury.to_account_info(),
-                },
-                signer,
-            ),
+        // Transfer marketplace fee, less the referrer's share
+        let referral_amount = pay_referral(
+            &payer,
+            &ctx.accounts.marketplace,
             marketplace_fee_amount,
+            &ctx.accounts.seller.key(),
+            &ctx.accounts.referrer,
+            &ctx.accounts.registered_referrer,
+            &ctx.accounts.referrer_payment_account,
+        )?;
+        payer.pay(
+            payment_account(
+                payer.payment_mint,
+                ctx.accounts.treasury.to_account_info(),
+                &ctx.accounts.treasury_payment_account,
+            )?,
+            marketplace_fee_amount - referral_amount,
         )?;
         
-        // Transfer NFT to bidder
-        let cpi_accounts = anchor_spl::token::Transfer {
-            from: ctx.accounts.seller_token_account.to_account_info(),
-            to: ctx.accounts.bidder_token_account.to_account_info(),
-            authority: ctx.accounts.seller.to_account_info(),
-        };
-        
-        let cpi_program = ctx.accounts.token_program.to_account_info();
-        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
-        
-        anchor_spl::token::transfer(cpi_ctx, 1)?;
+        // Close the drained escrow, returning its rent to the bidder
+        payer.close(ctx.accounts.bidder.to_account_info())?;
         
-        // Update bid status
-        let bid = &mut ctx.accounts.bid;
-        bid.active = false;
+        // Transfer NFT to bidder
+        if is_programmable(&ctx.accounts.metadata)? {
+            transfer_programmable_nft(
+                ProgrammableTransfer {
+                    token: ctx.accounts.seller_token_account.to_account_info(),
+                    token_owner: ctx.accounts.seller.to_account_info(),
+                    destination: ctx.accounts.bidder_token_account.to_account_info(),
+                    destination_owner: ctx.accounts.bidder.to_account_info(),
+                    mint: ctx.accounts.mint.to_account_info(),
+                    metadata: ctx.accounts.metadata.to_account_info(),
+                    edition: required_account(&ctx.accounts.edition)?,
+                    owner_token_record: required_account(&ctx.accounts.owner_token_record)?,
+                    destination_token_record: required_account(&ctx.accounts.destination_token_record)?,
+                    authority: ctx.accounts.seller.to_account_info(),
+                    payer: ctx.accounts.seller.to_account_info(),
+                    system_program: ctx.accounts.system_program.to_account_info(),
+                    sysvar_instructions: required_account(&ctx.accounts.sysvar_instructions)?,
+                    token_program: ctx.accounts.token_program.to_account_info(),
+                    associated_token_program: ctx.accounts.associated_token_program.to_account_info(),
+                    token_metadata_program: required_account(&ctx.accounts.token_metadata_program)?,
+                    authorization_rules_program: optional_account(&ctx.accounts.authorization_rules_program),
+                    authorization_rules: optional_account(&ctx.accounts.authorization_rules),
+                },
+                &[],
+            )?;
+        } else {
+            transfer_nft(
+                ctx.accounts.token_program.to_account_info(),
+                ctx.accounts.seller_token_account.to_account_info(),
+                &ctx.accounts.mint,
+                ctx.accounts.bidder_token_account.to_account_info(),
+                ctx.accounts.seller.to_account_info(),
+                &ctx.remaining_accounts[royalties.len()..],
+                &[],
+            )?;
+        }
         
         // Emit event
         emit!(BidAccepted {
@@ -409,34 +1785,42 @@ pub mod nft_marketplace {
     
     // Cancel a bid
     pub fn cancel_bid(ctx: Context<CancelBid>) -> Result<()> {
-        let bid = &mut ctx.accounts.bid;
+        let bid = &ctx.accounts.bid;
         
         // Verify the bid is active
         require!(bid.active, NftMarketplaceError::InactiveBid);
         
         // Return funds to bidder
         let seeds = &[
-            b"bid_escrow".as_ref(),
+            b"bid".as_ref(),
+            bid.marketplace.as_ref(),
             bid.mint.as_ref(),
             bid.bidder.as_ref(),
             &[bid.bump],
         ];
         let signer = &[&seeds[..]];
         
-        anchor_lang::system_program::transfer(
-            CpiContext::new_with_signer(
+        let payment_mint = order_payment_mint(bid.payment_mint, &ctx.accounts.payment_mint)?;
+        let payer = Payer {
+            from: order_escrow(payment_mint, &ctx.accounts.escrow, &ctx.accounts.payment_escrow)?,
+            authority: bid.to_account_info(),
+            payment_mint,
+            program: payment_account(
+                payment_mint,
                 ctx.accounts.system_program.to_account_info(),
-                anchor_lang::system_program::Transfer {
-                    from: ctx.accounts.escrow.to_account_info(),
-                    to: ctx.accounts.bidder.to_account_info(),
-                },
-                signer,
-            ),
-            bid.price,
+                &ctx.accounts.payment_token_program,
+            )?,
+            signer_seeds: signer,
+        };
+        payer.pay(
+            payment_account(
+                payment_mint,
+                ctx.accounts.bidder.to_account_info(),
+                &ctx.accounts.bidder_payment_account,
+            )?,
+            bid.escrowed_amount()?,
         )?;
-        
-        // Update bid status
-        bid.active = false;
+        payer.close(ctx.accounts.bidder.to_account_info())?;
         
         // Emit event
         emit!(BidCancelled {
@@ -448,366 +1832,4171 @@ pub mod nft_marketplace {
         msg!("Bid cancelled for NFT");
         Ok(())
     }
-}
-
-#[derive(Accounts)]
-#[instruction(marketplace_fee: u64)]
-pub struct InitializeMarketplace<'info> {
-    #[account(
-        init,
-        payer = authority,
-        space = 8 + 32 + 8 + 32 + 1,
-        seeds = [b"marketplace"],
-        bump
-    )]
-    pub marketplace: Account<'info, Marketplace>,
-    
-    #[account(mut)]
-    pub authority: Signer<'info>,
-    
-    /// CHECK: This is the treasury account that will receive fees
-    pub treasury: AccountInfo<'info>,
-    
-    pub system_program: Program<'info, System>,
-}
 
-#[derive(Accounts)]
-#[instruction(price: u64, auction_house_fee: u64)]
-pub struct ListNft<'info> {
-    #[account(
-        init,
-        payer = seller,
-        space = 8 + 32 + 32 + 8 + 1 + 8 + 1,
-        seeds = [b"listing", mint.key().as_ref(), seller.key().as_ref()],
-        bump
-    )]
-    pub listing: Account<'info, Listing>,
-    
-    pub marketplace: Account<'info, Marketplace>,
-    
-    #[account(mut)]
-    pub seller: Signer<'info>,
-    
-    pub mint: Account<'info, Mint>,
-    
-    #[account(
-        constraint = seller_token_account.owner == seller.key(),
-        constraint = seller_token_account.mint == mint.key(),
-        constraint = seller_token_account.amount == 1
-    )]
-    pub seller_token_account: Account<'info, TokenAccount>,
-    
-    pub system_program: Program<'info, System>,
-}
+    // Start an English auction, escrowing the NFT until it is settled
+    //
+    // Bids placed within `extension` seconds of the end push the end back to
+    // `extension` seconds after the bid. Extra accounts required by a
+    // Token-2022 transfer hook are passed as remaining accounts.
+    pub fn create_auction<'info>(
+        ctx: Context<'_, '_, '_, 'info, CreateAuction<'info>>,
+        reserve_price: u64,
+        min_increment: u64,
+        duration: i64,
+        extension: i64,
+    ) -> Result<()> {
+        require!(
+            duration > 0 && duration <= MAX_AUCTION_DURATION && (0..=MAX_AUCTION_DURATION).contains(&extension),
+            NftMarketplaceError::InvalidAuctionDuration
+        );
+        require!(min_increment > 0, NftMarketplaceError::InvalidMinIncrement);
+        require_transferable(&ctx.accounts.mint)?;
+        require!(
+            !is_programmable(&ctx.accounts.metadata)?,
+            NftMarketplaceError::UnsupportedListingMode
+        );
+        
+        let payment_mint = accepted_payment_mint(&ctx.accounts.marketplace, &ctx.accounts.payment_mint)?;
+        
+        // Apply the policy of the NFT's collection, if configured
+        let collection_config = collection_config(
+            &ctx.accounts.marketplace.key(),
+            &ctx.accounts.metadata,
+            ctx.accounts.collection_config.as_deref(),
+        )?;
+        require_collection_payment_mint(collection_config.as_ref(), payment_mint)?;
+        require_collection_min_price(collection_config.as_ref(), reserve_price)?;
+        
+        let now = Clock::get()?.unix_timestamp;
+        let auction = &mut ctx.accounts.auction;
+        auction.marketplace = ctx.accounts.marketplace.key();
+        auction.seller = ctx.accounts.seller.key();
+        auction.mint = ctx.accounts.mint.key();
+        auction.payment_mint = payment_mint;
+        auction.reserve_price = reserve_price;
+        auction.min_increment = min_increment;
+        auction.highest_bid = 0;
+        auction.highest_bid_fee = 0;
+        auction.highest_bidder = None;
+        auction.created_at = now;
+        auction.end_time = now
+            .checked_add(duration)
+            .ok_or(NftMarketplaceError::InvalidAuctionDuration)?;
+        auction.extension = extension;
+        auction.bump = ctx.bumps.auction;
+        auction.vault_bump = ctx.bumps.vault;
+        
+        // Move the NFT into the auction vault
+        transfer_nft(
+            ctx.accounts.token_program.to_account_info(),
+            ctx.accounts.seller_token_account.to_account_info(),
+            &ctx.accounts.mint,
+            ctx.accounts.vault.to_account_info(),
+            ctx.accounts.seller.to_account_info(),
+            ctx.remaining_accounts,
+            &[],
+        )?;
+        
+        msg!("Auction created with reserve price: {}", reserve_price);
+        Ok(())
+    }
 
-#[derive(Accounts)]
-pub struct CancelListing<'info> {
-    #[account(
-        mut,
-        seeds = [b"listing", listing.mint.as_ref(), seller.key().as_ref()],
-        bump = listing.bump,
-        constraint = listing.seller == seller.key(),
-        constraint = listing.active
-    )]
-    pub listing: Account<'info, Listing>,
-    
-    #[account(mut)]
-    pub seller: Signer<'info>,
-    
-    pub system_program: Program<'info, System>,
-}
+    // Bid on an English auction, refunding the previous highest bidder
+    //
+    // The bidder pays the taker fee on top of the bid. It is escrowed with
+    // the bid and refunded along with it if the bid is beaten.
+    pub fn place_auction_bid(ctx: Context<PlaceAuctionBid>, amount: u64) -> Result<()> {
+        let auction = &ctx.accounts.auction;
+        
+        // Verify the auction is running and the bid beats the current one
+        let now = Clock::get()?.unix_timestamp;
+        require!(now < auction.end_time, NftMarketplaceError::AuctionEnded);
+        require!(amount >= auction.minimum_bid()?, NftMarketplaceError::BidTooLow);
+        
+        // Apply the policy of the NFT's collection, if configured
+        let collection_config = collection_config(
+            &ctx.accounts.marketplace.key(),
+            &ctx.accounts.metadata,
+            ctx.accounts.collection_config.as_deref(),
+        )?;
+        let (taker_fee_amount, _) = trade_fees(
+            &ctx.accounts.marketplace,
+            collection_config.as_ref(),
+            amount,
+        );
+        let escrowed_amount = amount
+            .checked_add(taker_fee_amount)
+            .ok_or(NftMarketplaceError::EscrowOverflow)?;
+        
+        let payment_mint = order_payment_mint(auction.payment_mint, &ctx.accounts.payment_mint)?;
+        let program = payment_account(
+            payment_mint,
+            ctx.accounts.system_program.to_account_info(),
+            &ctx.accounts.payment_token_program,
+        )?;
+        
+        // Lamports are held by the auction account, tokens by a payment escrow owned by the auction
+        let escrow = payment_account(
+            payment_mint,
+            auction.to_account_info(),
+            &ctx.accounts.payment_escrow,
+        )?;
+        
+        // Escrow the new bid and its taker fee
+        Payer {
+            from: payment_account(
+                payment_mint,
+                ctx.accounts.bidder.to_account_info(),
+                &ctx.accounts.bidder_payment_account,
+            )?,
+            authority: ctx.accounts.bidder.to_account_info(),
+            payment_mint,
+            program: program.clone(),
+            signer_seeds: &[],
+        }
+        .pay(escrow.clone(), escrowed_amount)?;
+        
+        // Refund the previous highest bidder, taker fee included
+        if auction.highest_bidder.is_some() {
+            let previous_bidder = ctx
+                .accounts
+                .previous_bidder
+                .as_ref()
+                .ok_or(NftMarketplaceError::MissingListingAccount)?;
+            
+            let seeds = &[
+                b"auction".as_ref(),
+                auction.marketplace.as_ref(),
+                auction.mint.as_ref(),
+                auction.seller.as_ref(),
+                &[auction.bump],
+            ];
+            Payer {
+                from: escrow,
+                authority: auction.to_account_info(),
+                payment_mint,
+                program,
+                signer_seeds: &[&seeds[..]],
+            }
+            .pay(
+                payment_account(
+                    payment_mint,
+                    previous_bidder.to_account_info(),
+                    &ctx.accounts.previous_bidder_payment_account,
+                )?,
+                auction.escrowed_amount()?,
+            )?;
+        }
+        
+        // Record the new highest bid, extending the auction if it was about to end
+        let auction = &mut ctx.accounts.auction;
+        auction.highest_bid = amount;
+        auction.highest_bid_fee = taker_fee_amount;
+        auction.highest_bidder = Some(ctx.accounts.bidder.key());
+        if auction.end_time - now < auction.extension {
+            auction.end_time = now
+                .checked_add(auction.extension)
+                .ok_or(NftMarketplaceError::InvalidAuctionDuration)?;
+        }
+        
+        // Emit event
+        emit!(AuctionBidPlaced {
+            auction: auction.key(),
+            bidder: ctx.accounts.bidder.key(),
+            amount,
+            end_time: auction.end_time,
+        });
+        
+        msg!("Auction bid placed at: {}", amount);
+        Ok(())
+    }
 
-#[derive(Accounts)]
-pub struct BuyNft<'info> {
-    pub marketplace: Account<'info, Marketplace>,
-    
-    #[account(
-        mut,
-        seeds = [b"listing", listing.mint.as_ref(), seller.key().as_ref()],
-        bump = listing.bump,
-        constraint = listing.active
-    )]
-    pub listing: Account<'info, Listing>,
-    
-    #[account(mut)]
-    pub buyer: Signer<'info>,
-    
-    #[account(mut)]
-    /// CHECK: This is the seller account
-    pub seller: AccountInfo<'info>,
-    
-    #[account(mut)]
-    /// CHECK: This is the treasury account
-    pub treasury: AccountInfo<'info>,
-    
-    #[account(
-        mut,
-        constraint = seller_token_account.owner == seller.key(),
-        constraint = seller_token_account.mint == listing.mint,
-        constraint = seller_token_account.amount == 1
-    )]
-    pub seller_token_account: Account<'info, TokenAccount>,
-    
-    #[account(
-        init_if_needed,
-        payer = buyer,
-        associated_token::mint = mint,
-        associated_token::authority = buyer
-    )]
-    pub buyer_token_account: Account<'info, TokenAccount>,
-    
-    pub mint: Account<'info, Mint>,
-    
-    pub token_program: Program<'info, Token>,
-    pub associated_token_program: Program<'info, AssociatedToken>,
-    pub system_program: Program<'info, System>,
-    pub rent: Sysvar<'info, Rent>,
-}
+    // Settle an auction once it has ended
+    //
+    // The winning bid is split like a sale through `buy`, with the taker fee
+    // escrowed on top of it by the winner. Verified creators must be passed as
+    // writable remaining accounts, in the order they appear in the metadata,
+    // followed by any extra accounts required by a Token-2022 transfer hook.
+    // Without bids the NFT is returned to the seller.
+    pub fn settle_auction<'info>(ctx: Context<'_, '_, '_, 'info, SettleAuction<'info>>) -> Result<()> {
+        let auction = &ctx.accounts.auction;
+        
+        // Verify the auction has ended
+        require!(
+            Clock::get()?.unix_timestamp >= auction.end_time,
+            NftMarketplaceError::AuctionNotEnded
+        );
+        
+        let auction_key = auction.key();
+        let seeds = &[
+            b"auction".as_ref(),
+            auction.marketplace.as_ref(),
+            auction.mint.as_ref(),
+            auction.seller.as_ref(),
+            &[auction.bump],
+        ];
+        let signer = &[&seeds[..]];
+        let vault_seeds = &[
+            b"vault".as_ref(),
+            auction_key.as_ref(),
+            &[auction.vault_bump],
+        ];
+        let vault_signer = &[&vault_seeds[..]];
+        
+        let (destination, hook_accounts) = match auction.highest_bidder {
+            Some(_) => {
+                // Apply the policy of the NFT's collection, if configured
+                let collection_config = collection_config(
+                    &ctx.accounts.marketplace.key(),
+                    &ctx.accounts.metadata,
+                    ctx.accounts.collection_config.as_deref(),
+                )?;
+                
+                // Calculate fees
+                let royalties = creator_royalties(
+                    &ctx.accounts.metadata,
+                    &ctx.accounts.mint.to_account_info(),
+                    auction.highest_bid,
+                    min_royalty(collection_config.as_ref()),
+                )?;
+                // The winner's taker fee was escrowed with the bid, and the
+                // seller's maker fee or rebate is settled out of the proceeds
+                let (_, maker_fee_amount) = trade_fees(
+                    &ctx.accounts.marketplace,
+                    collection_config.as_ref(),
+                    auction.highest_bid,
+                );
+                let (marketplace_fee_amount, maker_fee_amount) = auction.settlement_fees(maker_fee_amount)?;
+                let seller_amount = sale_proceeds(auction.highest_bid, maker_fee_amount, &royalties)?;
+                
+                let payment_mint = order_payment_mint(auction.payment_mint, &ctx.accounts.payment_mint)?;
+                let payer = Payer {
+                    from: payment_account(
+                        payment_mint,
+                        auction.to_account_info(),
+                        &ctx.accounts.payment_escrow,
+                    )?,
+                    authority: auction.to_account_info(),
+                    payment_mint,
+                    program: payment_account(
+                        payment_mint,
+                        ctx.accounts.system_program.to_account_info(),
+                        &ctx.accounts.payment_token_program,
+                    )?,
+                    signer_seeds: signer,
+                };
+                
+                // Pay creator royalties from escrow
+                pay_royalties(&payer, &royalties, ctx.remaining_accounts)?;
+                
+                // Transfer payment from escrow to seller
+                payer.pay(
+                    payment_account(
+                        payment_mint,
+                        ctx.accounts.seller.to_account_info(),
+                        &ctx.accounts.seller_payment_account,
+                    )?,
+                    seller_amount,
+                )?;
+                
+                // Transfer marketplace fee
+                payer.pay(
+                    payment_account(
+                        payment_mint,
+                        ctx.accounts.treasury.to_account_info(),
+                        &ctx.accounts.treasury_payment_account,
+                    )?,
+                    marketplace_fee_amount,
+                )?;
+                
+                // Close the drained payment escrow; the auction account itself
+                // is closed to the seller on exit
+                if payment_mint.is_some() {
+                    payer.close(ctx.accounts.seller.to_account_info())?;
+                }
+                
+                (
+                    ctx
+                    .accounts
+                    .winner_token_account
+                    .as_ref()
+                    .ok_or(NftMarketplaceError::MissingListingAccount)?
+                    .to_account_info(),
+                    &ctx.remaining_accounts[royalties.len()..],
+                )
+            }
+            None => (
+                ctx
+                    .accounts
+                    .seller_token_account
+                    .as_ref()
+                    .ok_or(NftMarketplaceError::MissingListingAccount)?
+                    .to_account_info(),
+                ctx.remaining_accounts,
+            ),
+        };
+        
+        // Transfer NFT to the winner, or back to the seller
+        transfer_nft(
+            ctx.accounts.token_program.to_account_info(),
+            ctx.accounts.vault.to_account_info(),
+            &ctx.accounts.mint,
+            destination,
+            ctx.accounts.vault.to_account_info(),
+            hook_accounts,
+            vault_signer,
+        )?;
+        
+        // Close the emptied vault, returning its rent to the seller
+        anchor_spl::token_interface::close_account(CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            anchor_spl::token_interface::CloseAccount {
+                account: ctx.accounts.vault.to_account_info(),
+                destination: ctx.accounts.seller.to_account_info(),
+                authority: ctx.accounts.vault.to_account_info(),
+            },
+            vault_signer,
+        ))?;
+        
+        // Emit event
+        emit!(AuctionSettled {
+            auction: auction_key,
+            seller: auction.seller,
+            winner: auction.highest_bidder,
+            price: auction.highest_bid,
+        });
+        
+        msg!("Auction settled at: {}", auction.highest_bid);
+        Ok(())
+    }
 
-#[derive(Accounts)]
-#[instruction(price: u64, expiry_time: i64)]
-pub struct MakeOffer<'info> {
-    #[account(
-        init,
-        payer = buyer,
-        space = 8 + 32 + 32 + 8 + 8 + 8 + 1 + 1,
-        seeds = [b"offer", mint.key().as_ref(), buyer.key().as_ref()],
-        bump
-    )]
-    pub offer: Account<'info, Offer>,
-    
-    #[account(
-        init,
-        payer = buyer,
-        space = 0,
-        seeds = [b"escrow", mint.key().as_ref(), buyer.key().as_ref()],
-        bump
-    )]
-    /// CHECK: This is the escrow account
-    pub escrow: AccountInfo<'info>,
-    
-    #[account(mut)]
-    pub buyer: Signer<'info>,
-    
-    pub mint: Account<'info, Mint>,
-    
-    pub system_program: Program<'info, System>,
-}
+    // Offer `price` per NFT for up to `quantity` NFTs of a verified collection
+    //
+    // Passing `trait_root` restricts the offer to the mints in that trait set,
+    // as published when the offer is made.
+    pub fn make_collection_offer(
+        ctx: Context<MakeCollectionOffer>,
+        price: u64,
+        quantity: u64,
+        expiry_time: i64,
+    ) -> Result<()> {
+        require!(quantity > 0, NftMarketplaceError::InvalidQuantity);
+        
+        let collection_offer = &mut ctx.accounts.collection_offer;
+        collection_offer.marketplace = ctx.accounts.marketplace.key();
+        collection_offer.buyer = ctx.accounts.buyer.key();
+        collection_offer.collection = ctx.accounts.collection.key();
+        collection_offer.price = price;
+        collection_offer.quantity = quantity;
+        collection_offer.payment_mint = accepted_payment_mint(&ctx.accounts.marketplace, &ctx.accounts.payment_mint)?;
+        collection_offer.created_at = Clock::get()?.unix_timestamp;
+        collection_offer.expires_at = expiry_time;
+        collection_offer.bump = ctx.bumps.collection_offer;
+        collection_offer.trait_root = ctx.accounts.trait_root.as_ref().map(|trait_root| trait_root.root);
+        
+        // Apply the policy of the collection, if configured
+        let collection_config = load_collection_config(
+            &ctx.accounts.marketplace.key(),
+            Some(ctx.accounts.collection.key()),
+            ctx.accounts.collection_config.as_deref(),
+        )?;
+        require_collection_payment_mint(collection_config.as_ref(), collection_offer.payment_mint)?;
+        
+        // Lock in the maker fee, escrowed on top of the price or deducted from it
+        // as a rebate
+        let (_, maker_fee_amount) = trade_fees(&ctx.accounts.marketplace, collection_config.as_ref(), price);
+        collection_offer.maker_fee = maker_fee_amount;
+        
+        // Escrow payment for every unit, in lamports held by the collection
+        // offer or in tokens held by the payment escrow
+        let payment_mint = ctx.accounts.payment_mint.as_ref();
+        Payer {
+            from: payment_account(
+                payment_mint,
+                ctx.accounts.buyer.to_account_info(),
+                &ctx.accounts.buyer_payment_account,
+            )?,
+            authority: ctx.accounts.buyer.to_account_info(),
+            payment_mint,
+            program: payment_account(
+                payment_mint,
+                ctx.accounts.system_program.to_account_info(),
+                &ctx.accounts.payment_token_program,
+            )?,
+            signer_seeds: &[],
+        }
+        .pay(
+            payment_account(
+                payment_mint,
+                collection_offer.to_account_info(),
+                &ctx.accounts.payment_escrow,
+            )?,
+            collection_offer.escrowed_total(quantity)?,
+        )?;
+        
+        msg!("Collection offer made for {} NFTs at price: {}", quantity, price);
+        Ok(())
+    }
 
-#[derive(Accounts)]
-pub struct AcceptOffer<'info> {
-    pub marketplace: Account<'info, Marketplace>,
-    
-    #[account(
-        mut,
-        seeds = [b"offer", offer.mint.as_ref(), offer.buyer.as_ref()],
-        bump = offer.bump,
-        constraint = offer.active
-    )]
-    pub offer: Account<'info, Offer>,
-    
-    #[account(
-        mut,
-        seeds = [b"escrow", offer.mint.as_ref(), offer.buyer.as_ref()],
-        bump
-    )]
-    /// CHECK: This is the escrow account
-    pub escrow: AccountInfo<'info>,
-    
-    #[account(mut)]
-    pub seller: Signer<'info>,
-    
-    #[account(mut)]
-    /// CHECK: This is the buyer account
-    pub buyer: AccountInfo<'info>,
-    
-    #[account(mut)]
-    /// CHECK: This is the treasury account
-    pub treasury: AccountInfo<'info>,
-    
-    #[account(
-        mut,
-        constraint = seller_token_account.owner == seller.key(),
+    // Sell an NFT of the offer's verified collection into a collection offer
+    //
+    // Trait offers additionally require `proof` that the mint is in the
+    // offer's trait set. Verified creators of the NFT must be passed as
+    // writable remaining accounts, in the order they appear in the metadata,
+    // followed by any extra accounts required by a Token-2022 transfer hook.
+    pub fn accept_collection_offer<'info>(
+        ctx: Context<'_, '_, '_, 'info, AcceptCollectionOffer<'info>>,
+        proof: Vec<[u8; 32]>,
+    ) -> Result<()> {
+        let collection_offer = &ctx.accounts.collection_offer;
+        
+        // Verify the offer is not expired and the NFT belongs to the collection
+        require!(
+            Clock::get()?.unix_timestamp <= collection_offer.expires_at,
+            NftMarketplaceError::ExpiredOffer
+        );
+        require_verified_collection(
+            &ctx.accounts.metadata,
+            &ctx.accounts.mint.key(),
+            &collection_offer.collection,
+        )?;
+        if let Some(trait_root) = collection_offer.trait_root {
+            require!(
+                verify_merkle_proof(&proof, trait_root, keccak::hash(ctx.accounts.mint.key().as_ref()).0),
+                NftMarketplaceError::InvalidTraitProof
+            );
+        }
+        
+        // Apply the policy of the collection, if configured
+        let collection_config = collection_config(
+            &ctx.accounts.marketplace.key(),
+            &ctx.accounts.metadata,
+            ctx.accounts.collection_config.as_deref(),
+        )?;
+        require_collection_payment_mint(collection_config.as_ref(), collection_offer.payment_mint)?;
+        
+        // Calculate fees
+        let royalties = creator_royalties(
+            &ctx.accounts.metadata,
+            &ctx.accounts.mint.to_account_info(),
+            collection_offer.price,
+            min_royalty(collection_config.as_ref()),
+        )?;
+        // The seller pays the taker fee out of the proceeds. The escrow holds the
+        // maker fee locked in by the buyer, or lacks its rebate. A rebate
+        // above the current taker fee cannot be funded, so the fill is rejected.
+        let (taker_fee_amount, _) = trade_fees(
+            &ctx.accounts.marketplace,
+            collection_config.as_ref(),
+            collection_offer.price,
+        );
+        let marketplace_fee_amount = taker_fee_amount
+            .checked_add_signed(collection_offer.maker_fee)
+            .ok_or(NftMarketplaceError::RebateExceedsTakerFee)?;
+        let seller_amount = sale_proceeds(collection_offer.escrowed_amount()?, marketplace_fee_amount as i64, &royalties)?;
+        
+        let seeds = &[
+            b"collection_offer".as_ref(),
+            collection_offer.marketplace.as_ref(),
+            collection_offer.collection.as_ref(),
+            collection_offer.buyer.as_ref(),
+            &[collection_offer.bump],
+        ];
+        let signer = &[&seeds[..]];
+        
+        // Lamports are held by the collection offer, tokens by a payment escrow it owns
+        let payment_mint = order_payment_mint(collection_offer.payment_mint, &ctx.accounts.payment_mint)?;
+        let payer = Payer {
+            from: payment_account(
+                payment_mint,
+                collection_offer.to_account_info(),
+                &ctx.accounts.payment_escrow,
+            )?,
+            authority: collection_offer.to_account_info(),
+            payment_mint,
+            program: payment_account(
+                payment_mint,
+                ctx.accounts.system_program.to_account_info(),
+                &ctx.accounts.payment_token_program,
+            )?,
+            signer_seeds: signer,
+        };
+        
+        // Pay creator royalties from escrow
+        pay_royalties(&payer, &royalties, ctx.remaining_accounts)?;
+        
+        // Transfer payment from escrow to seller
+        payer.pay(
+            payment_account(
+                payment_mint,
+                ctx.accounts.seller.to_account_info(),
+                &ctx.accounts.seller_payment_account,
+            )?,
+            seller_amount,
+        )?;
+        
+        // Transfer marketplace fee
+        payer.pay(
+            payment_account(
+                payment_mint,
+                ctx.accounts.treasury.to_account_info(),
+                &ctx.accounts.treasury_payment_account,
+            )?,
+            marketplace_fee_amount,
+        )?;
+        
+        // Close the drained payment escrow once the last unit is filled
+        if collection_offer.quantity == 1 && payment_mint.is_some() {
+            payer.close(ctx.accounts.buyer.to_account_info())?;
+        }
+        
+        // Transfer NFT to buyer
+        if is_programmable(&ctx.accounts.metadata)? {
+            transfer_programmable_nft(
+                ProgrammableTransfer {
+                    token: ctx.accounts.seller_token_account.to_account_info(),
+                    token_owner: ctx.accounts.seller.to_account_info(),
+                    destination: ctx.accounts.buyer_token_account.to_account_info(),
+                    destination_owner: ctx.accounts.buyer.to_account_info(),
+                    mint: ctx.accounts.mint.to_account_info(),
+                    metadata: ctx.accounts.metadata.to_account_info(),
+                    edition: required_account(&ctx.accounts.edition)?,
+                    owner_token_record: required_account(&ctx.accounts.owner_token_record)?,
+                    destination_token_record: required_account(&ctx.accounts.destination_token_record)?,
+                    authority: ctx.accounts.seller.to_account_info(),
+                    payer: ctx.accounts.seller.to_account_info(),
+                    system_program: ctx.accounts.system_program.to_account_info(),
+                    sysvar_instructions: required_account(&ctx.accounts.sysvar_instructions)?,
+                    token_program: ctx.accounts.token_program.to_account_info(),
+                    associated_token_program: ctx.accounts.associated_token_program.to_account_info(),
+                    token_metadata_program: required_account(&ctx.accounts.token_metadata_program)?,
+                    authorization_rules_program: optional_account(&ctx.accounts.authorization_rules_program),
+                    authorization_rules: optional_account(&ctx.accounts.authorization_rules),
+                },
+                &[],
+            )?;
+        } else {
+            transfer_nft(
+                ctx.accounts.token_program.to_account_info(),
+                ctx.accounts.seller_token_account.to_account_info(),
+                &ctx.accounts.mint,
+                ctx.accounts.buyer_token_account.to_account_info(),
+                ctx.accounts.seller.to_account_info(),
+                &ctx.remaining_accounts[royalties.len()..],
+                &[],
+            )?;
+        }
+        
+        // Decrement the remaining quantity, closing the offer once it is filled
+        let collection_offer = &mut ctx.accounts.collection_offer;
+        collection_offer.quantity -= 1;
+        if collection_offer.quantity == 0 {
+            collection_offer.close(ctx.accounts.buyer.to_account_info())?;
+        }
+        
+        msg!("Collection offer filled, {} remaining", collection_offer.quantity);
+        Ok(())
+    }
+
+    // Publish or replace the merkle root of the mints having a trait set,
+    // signed by the update authority of the collection
+    pub fn publish_trait_root(
+        ctx: Context<PublishTraitRoot>,
+        trait_id: [u8; 32],
+        root: [u8; 32],
+    ) -> Result<()> {
+        let metadata = Metadata::from_account_info(&ctx.accounts.collection_metadata)?;
+        require_keys_eq!(
+            metadata.update_authority,
+            ctx.accounts.authority.key(),
+            NftMarketplaceError::InvalidCollection
+        );
+        
+        let trait_root = &mut ctx.accounts.trait_root;
+        trait_root.collection = ctx.accounts.collection.key();
+        trait_root.trait_id = trait_id;
+        trait_root.root = root;
+        trait_root.bump = ctx.bumps.trait_root;
+        
+        msg!("Trait root published for collection: {}", trait_root.collection);
+        Ok(())
+    }
+
+    // Create or replace the terms negotiated with a collection, signed by the
+    // marketplace authority or the collection's update authority
+    //
+    // Fee overrides replace the marketplace rates for trades of the
+    // collection's NFTs, and only the marketplace authority can change them.
+    // Offers and bids keep the maker fee in force when they were made.
+    // Creators receive at least `min_royalty` basis points. A non-empty
+    // `payment_mints` restricts the currencies orders may use, with
+    // `Pubkey::default()` standing for SOL. Listings must be priced at or
+    // above `min_price`.
+    pub fn set_collection_config(
+        ctx: Context<SetCollectionConfig>,
+        maker_fee: Option<i64>,
+        taker_fee: Option<u64>,
+        min_royalty: u16,
+        payment_mints: Vec<Pubkey>,
+        min_price: u64,
+    ) -> Result<()> {
+        let marketplace = &ctx.accounts.marketplace;
+        let metadata = Metadata::from_account_info(&ctx.accounts.collection_metadata)?;
+        require!(
+            ctx.accounts.authority.key() == marketplace.authority
+                || ctx.accounts.authority.key() == metadata.update_authority,
+            NftMarketplaceError::InvalidConfigAuthority
+        );
+        if ctx.accounts.authority.key() != marketplace.authority {
+            require!(
+                maker_fee == ctx.accounts.collection_config.maker_fee
+                    && taker_fee == ctx.accounts.collection_config.taker_fee,
+                NftMarketplaceError::FeeOverrideNotAllowed
+            );
+        }
+        require_valid_fees(
+            maker_fee.unwrap_or(marketplace.maker_fee),
+            taker_fee.unwrap_or(marketplace.taker_fee),
+            marketplace.max_fee,
+        )?;
+        require!(min_royalty <= 10000, NftMarketplaceError::InvalidRoyalty);
+        require!(
+            payment_mints.len() <= MAX_PAYMENT_MINTS,
+            NftMarketplaceError::TooManyPaymentMints
+        );
+        
+        let collection_config = &mut ctx.accounts.collection_config;
+        collection_config.marketplace = marketplace.key();
+        collection_config.collection = ctx.accounts.collection.key();
+        collection_config.maker_fee = maker_fee;
+        collection_config.taker_fee = taker_fee;
+        collection_config.min_royalty = min_royalty;
+        collection_config.payment_mints = payment_mints;
+        collection_config.min_price = min_price;
+        collection_config.bump = ctx.bumps.collection_config;
+        
+        msg!("Collection config set for collection: {}", collection_config.collection);
+        Ok(())
+    }
+
+    // List several NFTs for sale as one lot
+    //
+    // Each NFT is passed as five remaining accounts: its mint, its metadata,
+    // the config of its verified collection (any account otherwise), the
+    // seller's token account and the bundle's associated token account, which
+    // is created to escrow the NFT. Programmable NFTs cannot be bundled.
+    #[allow(clippy::manual_is_multiple_of)]
+    pub fn list_bundle<'info>(
+        ctx: Context<'_, '_, 'info, 'info, ListBundle<'info>>,
+        bundle_id: u64,
+        price: u64,
+    ) -> Result<()> {
+        let items = ctx.remaining_accounts;
+        require!(
+            !items.is_empty() && items.len() % 5 == 0 && items.len() / 5 <= MAX_BUNDLE_SIZE,
+            NftMarketplaceError::InvalidBundle
+        );
+        
+        let bundle = &mut ctx.accounts.bundle;
+        bundle.marketplace = ctx.accounts.marketplace.key();
+        bundle.seller = ctx.accounts.seller.key();
+        bundle.bundle_id = bundle_id;
+        bundle.price = price;
+        bundle.payment_mint = accepted_payment_mint(&ctx.accounts.marketplace, &ctx.accounts.payment_mint)?;
+        bundle.created_at = Clock::get()?.unix_timestamp;
+        bundle.bump = ctx.bumps.bundle;
+        
+        // Move each NFT into a token account owned by the bundle
+        let mut collection_configs = Vec::with_capacity(items.len() / 5);
+        for item in items.chunks(5) {
+            let mint = InterfaceAccount::<Mint>::try_from(&item[0])?;
+            require_transferable(&mint)?;
+            require_keys_eq!(
+                item[1].key(),
+                mpl_token_metadata::pda::find_metadata_account(&mint.key()).0,
+                NftMarketplaceError::InvalidMetadata
+            );
+            require!(!is_programmable(&item[1])?, NftMarketplaceError::UnsupportedListingMode);
+            collection_configs.push(collection_config(&bundle.marketplace, &item[1], Some(&item[2]))?);
+            
+            create_token_account(
+                &ctx.accounts.associated_token_program,
+                ctx.accounts.seller.to_account_info(),
+                item[4].clone(),
+                bundle.to_account_info(),
+                item[0].clone(),
+                &ctx.accounts.system_program,
+                &ctx.accounts.token_program,
+            )?;
+            transfer_nft(
+                ctx.accounts.token_program.to_account_info(),
+                item[3].clone(),
+                &mint,
+                item[4].clone(),
+                ctx.accounts.seller.to_account_info(),
+                &[],
+                &[],
+            )?;
+            bundle.mints.push(mint.key());
+        }
+        
+        // Apply the policy of each NFT's collection to its share of the price
+        for (index, collection_config) in collection_configs.iter().enumerate() {
+            require_collection_payment_mint(collection_config.as_ref(), bundle.payment_mint)?;
+            require_collection_min_price(collection_config.as_ref(), bundle.item_price(index))?;
+        }
+        
+        msg!("Bundle of {} NFTs listed at price: {}", bundle.mints.len(), price);
+        Ok(())
+    }
+
+    // Buy every NFT of a bundle
+    //
+    // Each NFT is passed as five remaining accounts, in bundle order: its
+    // mint, its metadata, the config of its verified collection (any account
+    // otherwise), the bundle's token account and the buyer's associated token
+    // account. These are followed by the verified creators of every NFT, in
+    // bundle order and then metadata order.
+    pub fn buy_bundle<'info>(
+        ctx: Context<'_, '_, 'info, 'info, BuyBundle<'info>>,
+        max_price: u64,
+    ) -> Result<()> {
+        let bundle = &ctx.accounts.bundle;
+        require!(bundle.price <= max_price, NftMarketplaceError::PriceAboveMaximum);
+        
+        let count = bundle.mints.len();
+        require!(
+            ctx.remaining_accounts.len() >= count * 5,
+            NftMarketplaceError::InvalidBundle
+        );
+        let (items, creator_accounts) = ctx.remaining_accounts.split_at(count * 5);
+        
+        // Calculate fees item by item, splitting the price evenly. The buyer
+        // pays the taker fee on top, as in `buy`.
+        let mut royalties = Vec::new();
+        let mut item_fees = Vec::with_capacity(count);
+        let mut seller_amount: u64 = 0;
+        for (index, item) in items.chunks(5).enumerate() {
+            require_keys_eq!(item[0].key(), bundle.mints[index], NftMarketplaceError::InvalidBundle);
+            require_keys_eq!(
+                item[1].key(),
+                mpl_token_metadata::pda::find_metadata_account(&item[0].key()).0,
+                NftMarketplaceError::InvalidMetadata
+            );
+            
+            let collection_config = collection_config(&bundle.marketplace, &item[1], Some(&item[2]))?;
+            require_collection_payment_mint(collection_config.as_ref(), bundle.payment_mint)?;
+            
+            let item_price = bundle.item_price(index);
+            let item_royalties = creator_royalties(
+                &item[1],
+                &item[0],
+                item_price,
+                min_royalty(collection_config.as_ref()),
+            )?;
+            let (taker_fee_amount, maker_fee_amount) = trade_fees(
+                &ctx.accounts.marketplace,
+                collection_config.as_ref(),
+                item_price,
+            );
+            let item_proceeds = sale_proceeds(item_price, maker_fee_amount, &item_royalties)?;
+            let item_fee = taker_fee_amount
+                .checked_add_signed(maker_fee_amount)
+                .ok_or(NftMarketplaceError::RebateExceedsTakerFee)?;
+            
+            item_fees.push(item_fee);
+            seller_amount = seller_amount
+                .checked_add(item_proceeds)
+                .ok_or(NftMarketplaceError::PriceOverflow)?;
+            royalties.extend(item_royalties);
+        }
+        let marketplace_fee_amount: u64 = item_fees.iter().sum();
+        
+        let payment_mint = order_payment_mint(bundle.payment_mint, &ctx.accounts.payment_mint)?;
+        let payer = Payer {
+            from: payment_account(
+                payment_mint,
+                ctx.accounts.buyer.to_account_info(),
+                &ctx.accounts.buyer_payment_account,
+            )?,
+            authority: ctx.accounts.buyer.to_account_info(),
+            payment_mint,
+            program: payment_account(
+                payment_mint,
+                ctx.accounts.system_program.to_account_info(),
+                &ctx.accounts.payment_token_program,
+            )?,
+            signer_seeds: &[],
+        };
+        
+        // Pay creator royalties
+        pay_royalties(&payer, &royalties, creator_accounts)?;
+        
+        // Transfer payment to seller
+        payer.pay(
+            payment_account(
+                payment_mint,
+                ctx.accounts.seller.to_account_info(),
+                &ctx.accounts.seller_payment_account,
+            )?,
+            seller_amount,
+        )?;
+        
+        // Transfer marketplace fee
+        payer.pay(
+            payment_account(
+                payment_mint,
+                ctx.accounts.treasury.to_account_info(),
+                &ctx.accounts.treasury_payment_account,
+            )?,
+            marketplace_fee_amount,
+        )?;
+        
+        // Transfer each NFT to the buyer
+        let bundle_id = bundle.bundle_id.to_le_bytes();
+        let seeds = &[
+            b"bundle".as_ref(),
+            bundle.marketplace.as_ref(),
+            bundle.seller.as_ref(),
+            bundle_id.as_ref(),
+            &[bundle.bump],
+        ];
+        let signer = &[&seeds[..]];
+        
+        for item in items.chunks(5) {
+            create_token_account(
+                &ctx.accounts.associated_token_program,
+                ctx.accounts.buyer.to_account_info(),
+                item[4].clone(),
+                ctx.accounts.buyer.to_account_info(),
+                item[0].clone(),
+                &ctx.accounts.system_program,
+                &ctx.accounts.token_program,
+            )?;
+            release_escrowed_nft(
+                &ctx.accounts.token_program,
+                &item[0],
+                item[3].clone(),
+                item[4].clone(),
+                bundle.to_account_info(),
+                ctx.accounts.seller.to_account_info(),
+                signer,
+            )?;
+        }
+        
+        // Emit event
+        emit!(BundleSold {
+            seller: bundle.seller,
+            buyer: ctx.accounts.buyer.key(),
+            mints: bundle.mints.clone(),
+            price: bundle.price,
+            item_fees,
+        });
+        
+        msg!("Bundle of {} NFTs purchased for {}", count, bundle.price);
+        Ok(())
+    }
+
+    // Cancel a bundle, returning every NFT to the seller
+    //
+    // Each NFT is passed as three remaining accounts, in bundle order: its
+    // mint, the bundle's token account and the seller's associated token
+    // account.
+    pub fn cancel_bundle<'info>(ctx: Context<'_, '_, 'info, 'info, CancelBundle<'info>>) -> Result<()> {
+        let bundle = &ctx.accounts.bundle;
+        let count = bundle.mints.len();
+        require!(
+            ctx.remaining_accounts.len() == count * 3,
+            NftMarketplaceError::InvalidBundle
+        );
+        
+        let bundle_id = bundle.bundle_id.to_le_bytes();
+        let seeds = &[
+            b"bundle".as_ref(),
+            bundle.marketplace.as_ref(),
+            bundle.seller.as_ref(),
+            bundle_id.as_ref(),
+            &[bundle.bump],
+        ];
+        let signer = &[&seeds[..]];
+        
+        for (index, item) in ctx.remaining_accounts.chunks(3).enumerate() {
+            require_keys_eq!(item[0].key(), bundle.mints[index], NftMarketplaceError::InvalidBundle);
+            create_token_account(
+                &ctx.accounts.associated_token_program,
+                ctx.accounts.seller.to_account_info(),
+                item[2].clone(),
+                ctx.accounts.seller.to_account_info(),
+                item[0].clone(),
+                &ctx.accounts.system_program,
+                &ctx.accounts.token_program,
+            )?;
+            release_escrowed_nft(
+                &ctx.accounts.token_program,
+                &item[0],
+                item[1].clone(),
+                item[2].clone(),
+                bundle.to_account_info(),
+                ctx.accounts.seller.to_account_info(),
+                signer,
+            )?;
+        }
+        
+        msg!("Bundle of {} NFTs cancelled", count);
+        Ok(())
+    }
+
+    // Propose swapping NFTs and lamports for a counterparty's NFTs and lamports
+    //
+    // Each offered NFT is passed as four remaining accounts: its mint, its
+    // metadata, the proposer's token account and the swap's associated token
+    // account, which is created to escrow the NFT. Offered lamports are held by
+    // the swap. Programmable NFTs cannot be swapped.
+    #[allow(clippy::manual_is_multiple_of)]
+    pub fn propose_swap<'info>(
+        ctx: Context<'_, '_, 'info, 'info, ProposeSwap<'info>>,
+        swap_id: u64,
+        counterparty: Pubkey,
+        offered_lamports: u64,
+        requested_mints: Vec<Pubkey>,
+        requested_lamports: u64,
+    ) -> Result<()> {
+        let items = ctx.remaining_accounts;
+        require!(
+            items.len() % 4 == 0
+                && items.len() / 4 <= MAX_SWAP_SIZE
+                && requested_mints.len() <= MAX_SWAP_SIZE,
+            NftMarketplaceError::InvalidSwap
+        );
+        require!(
+            !items.is_empty() || offered_lamports > 0,
+            NftMarketplaceError::InvalidSwap
+        );
+        require!(
+            !requested_mints.is_empty() || requested_lamports > 0,
+            NftMarketplaceError::InvalidSwap
+        );
+        
+        let swap = &mut ctx.accounts.swap;
+        swap.marketplace = ctx.accounts.marketplace.key();
+        swap.proposer = ctx.accounts.proposer.key();
+        swap.counterparty = counterparty;
+        swap.swap_id = swap_id;
+        swap.offered_lamports = offered_lamports;
+        swap.requested_mints = requested_mints;
+        swap.requested_lamports = requested_lamports;
+        swap.created_at = Clock::get()?.unix_timestamp;
+        swap.bump = ctx.bumps.swap;
+        
+        // Move each offered NFT into a token account owned by the swap
+        for item in items.chunks(4) {
+            let mint = InterfaceAccount::<Mint>::try_from(&item[0])?;
+            require_transferable(&mint)?;
+            require_keys_eq!(
+                item[1].key(),
+                mpl_token_metadata::pda::find_metadata_account(&mint.key()).0,
+                NftMarketplaceError::InvalidMetadata
+            );
+            require!(!is_programmable(&item[1])?, NftMarketplaceError::UnsupportedListingMode);
+            
+            create_token_account(
+                &ctx.accounts.associated_token_program,
+                ctx.accounts.proposer.to_account_info(),
+                item[3].clone(),
+                swap.to_account_info(),
+                item[0].clone(),
+                &ctx.accounts.system_program,
+                &ctx.accounts.token_program,
+            )?;
+            transfer_nft(
+                ctx.accounts.token_program.to_account_info(),
+                item[2].clone(),
+                &mint,
+                item[3].clone(),
+                ctx.accounts.proposer.to_account_info(),
+                &[],
+                &[],
+            )?;
+            swap.offered_mints.push(mint.key());
+        }
+        
+        // Escrow offered lamports in the swap
+        Payer {
+            from: ctx.accounts.proposer.to_account_info(),
+            authority: ctx.accounts.proposer.to_account_info(),
+            payment_mint: None,
+            program: ctx.accounts.system_program.to_account_info(),
+            signer_seeds: &[],
+        }
+        .pay(swap.to_account_info(), offered_lamports)?;
+        
+        msg!(
+            "Swap proposed: {} NFTs and {} lamports for {} NFTs and {} lamports",
+            swap.offered_mints.len(),
+            offered_lamports,
+            swap.requested_mints.len(),
+            requested_lamports
+        );
+        Ok(())
+    }
+
+    // Execute a swap as its counterparty
+    //
+    // Each offered NFT is passed as three remaining accounts, in swap order:
+    // its mint, the swap's token account and the counterparty's associated
+    // token account. These are followed by four accounts for each requested
+    // NFT, in swap order: its mint, its metadata, the counterparty's token
+    // account and the proposer's associated token account. Programmable NFTs
+    // cannot be requested. The taker fee is deducted from the lamports each
+    // side receives, and maker fees do not apply to swaps.
+    pub fn execute_swap<'info>(ctx: Context<'_, '_, 'info, 'info, ExecuteSwap<'info>>) -> Result<()> {
+        let swap = &ctx.accounts.swap;
+        let offered_count = swap.offered_mints.len();
+        let requested_count = swap.requested_mints.len();
+        require!(
+            ctx.remaining_accounts.len() == offered_count * 3 + requested_count * 4,
+            NftMarketplaceError::InvalidSwap
+        );
+        let (offered_items, requested_items) = ctx.remaining_accounts.split_at(offered_count * 3);
+        
+        // Pay the counterparty the offered lamports held by the swap
+        let (offered_fee, _) = trade_fees(&ctx.accounts.marketplace, None, swap.offered_lamports);
+        let counterparty_amount = sale_proceeds(swap.offered_lamports, offered_fee as i64, &[])?;
+        let escrow = Payer {
+            from: swap.to_account_info(),
+            authority: swap.to_account_info(),
+            payment_mint: None,
+            program: ctx.accounts.system_program.to_account_info(),
+            signer_seeds: &[],
+        };
+        escrow.pay(ctx.accounts.counterparty.to_account_info(), counterparty_amount)?;
+        escrow.pay(ctx.accounts.treasury.to_account_info(), offered_fee)?;
+        
+        // Pay the proposer the requested lamports
+        let (requested_fee, _) = trade_fees(&ctx.accounts.marketplace, None, swap.requested_lamports);
+        let proposer_amount = sale_proceeds(swap.requested_lamports, requested_fee as i64, &[])?;
+        let payer = Payer {
+            from: ctx.accounts.counterparty.to_account_info(),
+            authority: ctx.accounts.counterparty.to_account_info(),
+            payment_mint: None,
+            program: ctx.accounts.system_program.to_account_info(),
+            signer_seeds: &[],
+        };
+        payer.pay(ctx.accounts.proposer.to_account_info(), proposer_amount)?;
+        payer.pay(ctx.accounts.treasury.to_account_info(), requested_fee)?;
+        
+        // Transfer the offered NFTs to the counterparty
+        let swap_id = swap.swap_id.to_le_bytes();
+        let seeds = &[
+            b"swap".as_ref(),
+            swap.marketplace.as_ref(),
+            swap.proposer.as_ref(),
+            swap_id.as_ref(),
+            &[swap.bump],
+        ];
+        let signer = &[&seeds[..]];
+        
+        for (index, item) in offered_items.chunks(3).enumerate() {
+            require_keys_eq!(item[0].key(), swap.offered_mints[index], NftMarketplaceError::InvalidSwap);
+            create_token_account(
+                &ctx.accounts.associated_token_program,
+                ctx.accounts.counterparty.to_account_info(),
+                item[2].clone(),
+                ctx.accounts.counterparty.to_account_info(),
+                item[0].clone(),
+                &ctx.accounts.system_program,
+                &ctx.accounts.token_program,
+            )?;
+            release_escrowed_nft(
+                &ctx.accounts.token_program,
+                &item[0],
+                item[1].clone(),
+                item[2].clone(),
+                swap.to_account_info(),
+                ctx.accounts.proposer.to_account_info(),
+                signer,
+            )?;
+        }
+        
+        // Transfer the requested NFTs to the proposer
+        for (index, item) in requested_items.chunks(4).enumerate() {
+            require_keys_eq!(item[0].key(), swap.requested_mints[index], NftMarketplaceError::InvalidSwap);
+            let mint = InterfaceAccount::<Mint>::try_from(&item[0])?;
+            require_transferable(&mint)?;
+            require_keys_eq!(
+                item[1].key(),
+                mpl_token_metadata::pda::find_metadata_account(&mint.key()).0,
+                NftMarketplaceError::InvalidMetadata
+            );
+            require!(!is_programmable(&item[1])?, NftMarketplaceError::UnsupportedListingMode);
+            
+            create_token_account(
+                &ctx.accounts.associated_token_program,
+                ctx.accounts.counterparty.to_account_info(),
+                item[3].clone(),
+                ctx.accounts.proposer.to_account_info(),
+                item[0].clone(),
+                &ctx.accounts.system_program,
+                &ctx.accounts.token_program,
+            )?;
+            transfer_nft(
+                ctx.accounts.token_program.to_account_info(),
+                item[2].clone(),
+                &mint,
+                item[3].clone(),
+                ctx.accounts.counterparty.to_account_info(),
+                &[],
+                &[],
+            )?;
+        }
+        
+        // Emit event
+        emit!(SwapExecuted {
+            swap: swap.key(),
+            proposer: swap.proposer,
+            counterparty: swap.counterparty,
+            offered_mints: swap.offered_mints.clone(),
+            offered_lamports: swap.offered_lamports,
+            requested_mints: swap.requested_mints.clone(),
+            requested_lamports: swap.requested_lamports,
+        });
+        
+        msg!("Swap executed");
+        Ok(())
+    }
+
+    // Cancel a swap, returning the offered NFTs and lamports to the proposer
+    //
+    // Each offered NFT is passed as three remaining accounts, in swap order:
+    // its mint, the swap's token account and the proposer's associated token
+    // account.
+    pub fn cancel_swap<'info>(ctx: Context<'_, '_, 'info, 'info, CancelSwap<'info>>) -> Result<()> {
+        let swap = &ctx.accounts.swap;
+        require!(
+            ctx.remaining_accounts.len() == swap.offered_mints.len() * 3,
+            NftMarketplaceError::InvalidSwap
+        );
+        
+        let swap_id = swap.swap_id.to_le_bytes();
+        let seeds = &[
+            b"swap".as_ref(),
+            swap.marketplace.as_ref(),
+            swap.proposer.as_ref(),
+            swap_id.as_ref(),
+            &[swap.bump],
+        ];
+        let signer = &[&seeds[..]];
+        
+        for (index, item) in ctx.remaining_accounts.chunks(3).enumerate() {
+            require_keys_eq!(item[0].key(), swap.offered_mints[index], NftMarketplaceError::InvalidSwap);
+            create_token_account(
+                &ctx.accounts.associated_token_program,
+                ctx.accounts.proposer.to_account_info(),
+                item[2].clone(),
+                ctx.accounts.proposer.to_account_info(),
+                item[0].clone(),
+                &ctx.accounts.system_program,
+                &ctx.accounts.token_program,
+            )?;
+            release_escrowed_nft(
+                &ctx.accounts.token_program,
+                &item[0],
+                item[1].clone(),
+                item[2].clone(),
+                swap.to_account_info(),
+                ctx.accounts.proposer.to_account_info(),
+                signer,
+            )?;
+        }
+        
+        // Offered lamports are returned when the swap is closed
+        msg!("Swap cancelled");
+        Ok(())
+    }
+
+    // Cancel a collection offer, refunding the unfilled units
+    pub fn cancel_collection_offer(ctx: Context<CancelCollectionOffer>) -> Result<()> {
+        let collection_offer = &ctx.accounts.collection_offer;
+        
+        // Lamports held by the collection offer are refunded when it closes;
+        // tokens are returned from the payment escrow
+        let payment_mint = order_payment_mint(collection_offer.payment_mint, &ctx.accounts.payment_mint)?;
+        if payment_mint.is_some() {
+            let seeds = &[
+                b"collection_offer".as_ref(),
+                collection_offer.marketplace.as_ref(),
+                collection_offer.collection.as_ref(),
+                collection_offer.buyer.as_ref(),
+                &[collection_offer.bump],
+            ];
+            let payer = Payer {
+                from: payment_account(
+                    payment_mint,
+                    collection_offer.to_account_info(),
+                    &ctx.accounts.payment_escrow,
+                )?,
+                authority: collection_offer.to_account_info(),
+                payment_mint,
+                program: payment_account(
+                    payment_mint,
+                    ctx.accounts.system_program.to_account_info(),
+                    &ctx.accounts.payment_token_program,
+                )?,
+                signer_seeds: &[&seeds[..]],
+            };
+            payer.pay(
+                payment_account(
+                    payment_mint,
+                    ctx.accounts.buyer.to_account_info(),
+                    &ctx.accounts.buyer_payment_account,
+                )?,
+                collection_offer.escrowed_total(collection_offer.quantity)?,
+            )?;
+            payer.close(ctx.accounts.buyer.to_account_info())?;
+        }
+        
+        msg!("Collection offer cancelled");
+        Ok(())
+    }
+}
+
+// Requires both fees within `max_fee`, with maker rebates funded by the taker fee
+fn require_valid_fees(maker_fee: i64, taker_fee: u64, max_fee: u64) -> Result<()> {
+    require!(
+        taker_fee <= max_fee && maker_fee <= max_fee as i64 && maker_fee >= -(taker_fee as i64),
+        NftMarketplaceError::InvalidFee
+    );
+    Ok(())
+}
+
+// Returns `basis_points` of `amount`
+fn basis_points(amount: u64, basis_points: u64) -> u64 {
+    (amount as u128)
+        .checked_mul(basis_points as u128)
+        .unwrap()
+        .checked_div(10000)
+        .unwrap() as u64
+}
+
+// Returns the taker and maker fees of a trade at `price`, at the marketplace
+// rates unless the collection config overrides them. The maker fee is negative
+// for a rebate, which never exceeds the taker fee funding it.
+pub fn trade_fees(
+    marketplace: &Marketplace,
+    collection_config: Option<&CollectionConfig>,
+    price: u64,
+) -> (u64, i64) {
+    let maker_fee = collection_config
+        .and_then(|config| config.maker_fee)
+        .unwrap_or(marketplace.maker_fee);
+    let taker_fee = collection_config
+        .and_then(|config| config.taker_fee)
+        .unwrap_or(marketplace.taker_fee);
+    
+    let taker_fee_amount = basis_points(price, taker_fee);
+    let maker_fee_amount = if maker_fee >= 0 {
+        basis_points(price, maker_fee as u64) as i64
+    } else {
+        -(basis_points(price, maker_fee.unsigned_abs()).min(taker_fee_amount) as i64)
+    };
+    (taker_fee_amount, maker_fee_amount)
+}
+
+// Loads the config of the NFT's verified collection, if the collection has
+// one. The config account must be passed for any NFT in a verified collection,
+// so that its policy cannot be bypassed by omitting it.
+fn collection_config(
+    marketplace: &Pubkey,
+    metadata_account: &AccountInfo,
+    config_account: Option<&AccountInfo>,
+) -> Result<Option<CollectionConfig>> {
+    if metadata_account.data_is_empty() {
+        return Ok(None);
+    }
+    let metadata = Metadata::from_account_info(metadata_account)?;
+    let collection = metadata
+        .collection
+        .filter(|collection| collection.verified)
+        .map(|collection| collection.key);
+    load_collection_config(marketplace, collection, config_account)
+}
+
+// Loads the config of a compressed NFT's verified collection, if the
+// collection has one. `metadata` must already be verified against the leaf.
+fn compressed_collection_config(
+    marketplace: &Pubkey,
+    metadata: &MetadataArgs,
+    config_account: Option<&AccountInfo>,
+) -> Result<Option<CollectionConfig>> {
+    let collection = metadata
+        .collection
+        .as_ref()
+        .filter(|collection| collection.verified)
+        .map(|collection| collection.key);
+    load_collection_config(marketplace, collection, config_account)
+}
+
+// Loads the config PDA of `collection` from `config_account`, which is
+// required whenever the NFT is in a verified collection
+fn load_collection_config(
+    marketplace: &Pubkey,
+    collection: Option<Pubkey>,
+    config_account: Option<&AccountInfo>,
+) -> Result<Option<CollectionConfig>> {
+    let Some(collection) = collection else {
+        return Ok(None);
+    };
+    let config_account = config_account.ok_or(NftMarketplaceError::MissingCollectionConfig)?;
+    let (address, _) = Pubkey::find_program_address(
+        &[b"collection_config", marketplace.as_ref(), collection.as_ref()],
+        &crate::ID,
+    );
+    require_keys_eq!(config_account.key(), address, NftMarketplaceError::MissingCollectionConfig);
+    
+    if config_account.data_is_empty() {
+        return Ok(None);
+    }
+    let data = config_account.try_borrow_data()?;
+    Ok(Some(CollectionConfig::try_deserialize(&mut &data[..])?))
+}
+
+// Requires the order's payment mint to be accepted by the collection config
+fn require_collection_payment_mint(
+    collection_config: Option<&CollectionConfig>,
+    payment_mint: Option<Pubkey>,
+) -> Result<()> {
+    if let Some(config) = collection_config {
+        require!(
+            config.payment_mints.is_empty()
+                || config.payment_mints.contains(&payment_mint.unwrap_or_default()),
+            NftMarketplaceError::PaymentMintNotAllowed
+        );
+    }
+    Ok(())
+}
+
+// Requires a listing price to meet the collection config's minimum
+fn require_collection_min_price(collection_config: Option<&CollectionConfig>, price: u64) -> Result<()> {
+    if let Some(config) = collection_config {
+        require!(price >= config.min_price, NftMarketplaceError::PriceBelowMinimum);
+    }
+    Ok(())
+}
+
+// Returns the seller's share of `amount` after royalties and `seller_fee`,
+// which is negative for a rebate
+pub fn sale_proceeds(amount: u64, seller_fee: i64, royalties: &[(Pubkey, u64)]) -> Result<u64> {
+    let royalty_amount: u64 = royalties.iter().map(|(_, amount)| amount).sum();
+    amount
+        .checked_add_signed(-seller_fee)
+        .and_then(|amount| amount.checked_sub(royalty_amount))
+        .ok_or_else(|| NftMarketplaceError::FeesExceedPrice.into())
+}
+
+// Returns the royalty owed to each verified creator of `mint` for a sale at `price`
+//
+// Token-2022 mints without a Metaplex metadata account fall back to the
+// metadata extension stored on the mint.
+pub fn creator_royalties(
+    metadata_account: &AccountInfo,
+    mint_account: &AccountInfo,
+    price: u64,
+    min_basis_points: u16,
+) -> Result<Vec<(Pubkey, u64)>> {
+    if metadata_account.data_is_empty() {
+        return on_mint_royalties(mint_account, price);
+    }
+
+    let metadata = Metadata::from_account_info(metadata_account)?;
+    require_keys_eq!(metadata.mint, mint_account.key(), NftMarketplaceError::InvalidMetadata);
+
+    Ok(royalty_shares(
+        price,
+        metadata.data.seller_fee_basis_points.max(min_basis_points),
+        metadata
+            .data
+            .creators
+            .unwrap_or_default()
+            .iter()
+            .filter(|creator| creator.verified)
+            .map(|creator| (creator.address, creator.share)),
+    ))
+}
+
+// Requires `metadata` to be the compressed NFT's metadata committed to by `leaf`
+fn require_compressed_metadata(leaf: &CompressedLeaf, metadata: &MetadataArgs) -> Result<()> {
+    let data_hash = hash_metadata(metadata).map_err(|_| NftMarketplaceError::InvalidCompressedMetadata)?;
+    require!(
+        data_hash == leaf.data_hash && hash_creators(&metadata.creators) == leaf.creator_hash,
+        NftMarketplaceError::InvalidCompressedMetadata
+    );
+    Ok(())
+}
+
+// Returns the royalty owed to each verified creator of a compressed NFT for a
+// sale at `price`
+pub fn compressed_royalties(metadata: &MetadataArgs, price: u64, min_basis_points: u16) -> Vec<(Pubkey, u64)> {
+    royalty_shares(
+        price,
+        metadata.seller_fee_basis_points.max(min_basis_points),
+        metadata
+            .creators
+            .iter()
+            .filter(|creator| creator.verified)
+            .map(|creator| (creator.address, creator.share)),
+    )
+}
+
+// Splits the royalty at `basis_points` of `price` between creators by their
+// percentage shares
+fn royalty_shares(
+    price: u64,
+    basis_points: u16,
+    creators: impl Iterator<Item = (Pubkey, u8)>,
+) -> Vec<(Pubkey, u64)> {
+    let royalty_amount = (price as u128)
+        .checked_mul(basis_points as u128)
+        .unwrap()
+        .checked_div(10000)
+        .unwrap();
+    creators
+        .map(|(address, share)| {
+            let amount = royalty_amount
+                .checked_mul(share as u128)
+                .unwrap()
+                .checked_div(100)
+                .unwrap() as u64;
+            (address, amount)
+        })
+        .collect()
+}
+
+// Reads royalties from the Token-2022 metadata extension on the mint. The
+// `seller_fee_basis_points` additional field is paid to the update authority.
+fn on_mint_royalties(mint_account: &AccountInfo, price: u64) -> Result<Vec<(Pubkey, u64)>> {
+    require_keys_eq!(
+        *mint_account.owner,
+        spl_token_2022::ID,
+        NftMarketplaceError::InvalidMetadata
+    );
+
+    let data = mint_account.try_borrow_data()?;
+    let mint = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&data)?;
+    let metadata = match mint.get_variable_len_extension::<TokenMetadata>() {
+        Ok(metadata) => metadata,
+        Err(_) => return Ok(Vec::new()),
+    };
+    require_keys_eq!(metadata.mint, mint_account.key(), NftMarketplaceError::InvalidMetadata);
+
+    let seller_fee_basis_points = match metadata
+        .additional_metadata
+        .iter()
+        .find(|(key, _)| key == "seller_fee_basis_points")
+    {
+        Some((_, value)) => value
+            .parse::<u16>()
+            .ok()
+            .filter(|basis_points| *basis_points <= 10000)
+            .ok_or(NftMarketplaceError::InvalidMetadata)?,
+        None => 0,
+    };
+
+    let update_authority: Option<Pubkey> = metadata.update_authority.into();
+    Ok(match update_authority {
+        Some(creator) if seller_fee_basis_points > 0 => {
+            let amount = (price as u128)
+                .checked_mul(seller_fee_basis_points as u128)
+                .unwrap()
+                .checked_div(10000)
+                .unwrap() as u64;
+            vec![(creator, amount)]
+        }
+        _ => Vec::new(),
+    })
+}
+
+// Checks that the metadata of `mint` names `collection` as its verified collection
+pub fn require_verified_collection(
+    metadata_account: &AccountInfo,
+    mint: &Pubkey,
+    collection: &Pubkey,
+) -> Result<()> {
+    let metadata = Metadata::from_account_info(metadata_account)?;
+    require_keys_eq!(metadata.mint, *mint, NftMarketplaceError::InvalidMetadata);
+    require!(
+        matches!(metadata.collection, Some(ref c) if c.verified && c.key == *collection),
+        NftMarketplaceError::InvalidCollection
+    );
+    Ok(())
+}
+
+// Verifies that `leaf` is in the merkle tree with `root`. Each level hashes
+// the sorted pair of nodes with keccak256; leaves are the keccak256 of a mint.
+pub fn verify_merkle_proof(proof: &[[u8; 32]], root: [u8; 32], leaf: [u8; 32]) -> bool {
+    let computed = proof.iter().fold(leaf, |node, sibling| {
+        if node <= *sibling {
+            keccak::hashv(&[&node, sibling]).0
+        } else {
+            keccak::hashv(&[sibling, &node]).0
+        }
+    });
+    computed == root
+}
+
+// Returns whether the NFT is a programmable NFT that must move through Token Metadata
+pub fn is_programmable(metadata_account: &AccountInfo) -> Result<bool> {
+    if metadata_account.data_is_empty() {
+        return Ok(false);
+    }
+
+    let metadata = Metadata::from_account_info(metadata_account)?;
+    Ok(matches!(
+        metadata.token_standard,
+        Some(TokenStandard::ProgrammableNonFungible)
+    ))
+}
+
+// Transfers a single NFT with `transfer_checked` through SPL Token or Token-2022,
+// forwarding any extra accounts required by the mint's transfer hook
+pub fn transfer_nft<'info>(
+    token_program: AccountInfo<'info>,
+    from: AccountInfo<'info>,
+    mint: &InterfaceAccount<'info, Mint>,
+    to: AccountInfo<'info>,
+    authority: AccountInfo<'info>,
+    hook_accounts: &[AccountInfo<'info>],
+    signer_seeds: &[&[&[u8]]],
+) -> Result<()> {
+    transfer_units(token_program, from, mint, to, authority, hook_accounts, 1, signer_seeds)
+}
+
+// Transfers `amount` units of a semi-fungible mint, as `transfer_nft` does for one
+#[allow(clippy::too_many_arguments)]
+pub fn transfer_units<'info>(
+    token_program: AccountInfo<'info>,
+    from: AccountInfo<'info>,
+    mint: &InterfaceAccount<'info, Mint>,
+    to: AccountInfo<'info>,
+    authority: AccountInfo<'info>,
+    hook_accounts: &[AccountInfo<'info>],
+    amount: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> Result<()> {
+    spl_token_2022::onchain::invoke_transfer_checked(
+        token_program.key,
+        from,
+        mint.to_account_info(),
+        to,
+        authority,
+        hook_accounts,
+        amount,
+        mint.decimals,
+        signer_seeds,
+    )?;
+    Ok(())
+}
+
+// Rejects Token-2022 mints carrying the non-transferable extension
+pub fn require_transferable(mint: &InterfaceAccount<Mint>) -> Result<()> {
+    let mint_info = mint.to_account_info();
+    if mint_info.owner == &spl_token_2022::ID {
+        let data = mint_info.try_borrow_data()?;
+        let state = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&data)?;
+        require!(
+            state.get_extension::<NonTransferable>().is_err(),
+            NftMarketplaceError::NonTransferableMint
+        );
+    }
+    Ok(())
+}
+
+// Returns the payment mint of a new order, which must be on the marketplace
+// allowlist. Orders without a payment mint are priced in lamports.
+fn accepted_payment_mint(
+    marketplace: &Marketplace,
+    payment_mint: &Option<InterfaceAccount<Mint>>,
+) -> Result<Option<Pubkey>> {
+    match payment_mint {
+        Some(payment_mint) => {
+            require!(
+                marketplace.payment_mints.contains(&payment_mint.key()),
+                NftMarketplaceError::PaymentMintNotAllowed
+            );
+            Ok(Some(payment_mint.key()))
+        }
+        None => Ok(None),
+    }
+}
+
+// Returns the mint account an existing order is priced in, if any
+fn order_payment_mint<'a, 'info>(
+    payment_mint: Option<Pubkey>,
+    account: &'a Option<InterfaceAccount<'info, Mint>>,
+) -> Result<Option<&'a InterfaceAccount<'info, Mint>>> {
+    match payment_mint {
+        Some(payment_mint) => {
+            let account = account
+                .as_ref()
+                .ok_or(NftMarketplaceError::MissingPaymentAccount)?;
+            require_keys_eq!(account.key(), payment_mint, NftMarketplaceError::InvalidPaymentMint);
+            Ok(Some(account))
+        }
+        None => Ok(None),
+    }
+}
+
+// Picks the lamport account for SOL orders, or the optional token account or
+// token program for orders priced in an SPL token
+fn payment_account<'info, T: ToAccountInfo<'info>>(
+    payment_mint: Option<&InterfaceAccount<'info, Mint>>,
+    lamports: AccountInfo<'info>,
+    tokens: &Option<T>,
+) -> Result<AccountInfo<'info>> {
+    match payment_mint {
+        Some(_) => tokens
+            .as_ref()
+            .map(|account| account.to_account_info())
+            .ok_or_else(|| error!(NftMarketplaceError::MissingPaymentAccount)),
+        None => Ok(lamports),
+    }
+}
+
+// Picks the escrow holding an offer or bid payment: the lamport escrow PDA for
+// SOL orders or the payment escrow token account otherwise
+fn order_escrow<'info>(
+    payment_mint: Option<&InterfaceAccount<'info, Mint>>,
+    escrow: &Option<UncheckedAccount<'info>>,
+    payment_escrow: &Option<InterfaceAccount<'info, TokenAccount>>,
+) -> Result<AccountInfo<'info>> {
+    let escrow = match payment_mint {
+        Some(_) => payment_escrow.as_ref().map(|escrow| escrow.to_account_info()),
+        None => escrow.as_ref().map(|escrow| escrow.to_account_info()),
+    };
+    escrow.ok_or_else(|| error!(NftMarketplaceError::MissingPaymentAccount))
+}
+
+// Source of settlement payments: lamports from a wallet or program-owned
+// escrow, or tokens of the order's payment mint moved by its token program
+pub struct Payer<'a, 'info> {
+    pub from: AccountInfo<'info>,
+    pub authority: AccountInfo<'info>,
+    pub payment_mint: Option<&'a InterfaceAccount<'info, Mint>>,
+    pub program: AccountInfo<'info>,
+    pub signer_seeds: &'a [&'a [&'a [u8]]],
+}
+
+impl<'a, 'info> Payer<'a, 'info> {
+    // Pays `amount` to a wallet for SOL orders or to a token account of the payment mint
+    pub fn pay(&self, to: AccountInfo<'info>, amount: u64) -> Result<()> {
+        if amount == 0 {
+            return Ok(());
+        }
+        match self.payment_mint {
+            Some(payment_mint) => anchor_spl::token_interface::transfer_checked(
+                CpiContext::new_with_signer(
+                    self.program.clone(),
+                    anchor_spl::token_interface::TransferChecked {
+                        from: self.from.clone(),
+                        mint: payment_mint.to_account_info(),
+                        to,
+                        authority: self.authority.clone(),
+                    },
+                    self.signer_seeds,
+                ),
+                amount,
+                payment_mint.decimals,
+            ),
+            // The System program can only debit system-owned accounts, so
+            // program-owned escrows are debited directly
+            None if self.from.owner == &crate::ID => {
+                **self.from.try_borrow_mut_lamports()? = self
+                    .from
+                    .lamports()
+                    .checked_sub(amount)
+                    .ok_or(NftMarketplaceError::InsufficientFunds)?;
+                **to.try_borrow_mut_lamports()? = to.lamports().checked_add(amount).unwrap();
+                Ok(())
+            }
+            None => anchor_lang::system_program::transfer(
+                CpiContext::new_with_signer(
+                    self.program.clone(),
+                    anchor_lang::system_program::Transfer {
+                        from: self.from.clone(),
+                        to,
+                    },
+                    self.signer_seeds,
+                ),
+                amount,
+            ),
+        }
+    }
+
+    // Closes a drained escrow source, returning its rent to `receiver`
+    pub fn close(&self, receiver: AccountInfo<'info>) -> Result<()> {
+        match self.payment_mint {
+            Some(_) => anchor_spl::token_interface::close_account(CpiContext::new_with_signer(
+                self.program.clone(),
+                anchor_spl::token_interface::CloseAccount {
+                    account: self.from.clone(),
+                    destination: receiver,
+                    authority: self.authority.clone(),
+                },
+                self.signer_seeds,
+            )),
+            None => {
+                let lamports = self.from.lamports();
+                **receiver.try_borrow_mut_lamports()? = receiver.lamports().checked_add(lamports).unwrap();
+                **self.from.try_borrow_mut_lamports()? = 0;
+                Ok(())
+            }
+        }
+    }
+}
+
+// Pays the referrer, if any, its share of the marketplace fee and returns the
+// amount paid
+fn pay_referral<'info>(
+    payer: &Payer<'_, 'info>,
+    marketplace: &Account<Marketplace>,
+    marketplace_fee_amount: u64,
+    trader: &Pubkey,
+    referrer: &Option<UncheckedAccount<'info>>,
+    registered_referrer: &Option<Account<Referrer>>,
+    referrer_payment_account: &Option<InterfaceAccount<'info, TokenAccount>>,
+) -> Result<u64> {
+    let Some(referrer) = referrer else {
+        return Ok(0);
+    };
+    let registered_referrer = registered_referrer
+        .as_ref()
+        .ok_or(NftMarketplaceError::UnregisteredReferrer)?;
+    require!(
+        registered_referrer.marketplace == marketplace.key() && registered_referrer.wallet == referrer.key(),
+        NftMarketplaceError::UnregisteredReferrer
+    );
+    require_keys_neq!(referrer.key(), *trader, NftMarketplaceError::SelfReferral);
+    if let Some(referrer_payment_account) = referrer_payment_account {
+        require_keys_eq!(
+            referrer_payment_account.owner,
+            referrer.key(),
+            NftMarketplaceError::InvalidReferrerAccount
+        );
+    }
+    let referral_amount = basis_points(marketplace_fee_amount, marketplace.referral_fee);
+    payer.pay(
+        payment_account(payer.payment_mint, referrer.to_account_info(), referrer_payment_account)?,
+        referral_amount,
+    )?;
+    Ok(referral_amount)
+}
+
+// Pays each verified creator its royalty. Creator accounts are the creators'
+// wallets for SOL orders, or their token accounts of the payment mint.
+fn pay_royalties<'info>(
+    payer: &Payer<'_, 'info>,
+    royalties: &[(Pubkey, u64)],
+    creator_accounts: &[AccountInfo<'info>],
+) -> Result<()> {
+    require!(
+        creator_accounts.len() >= royalties.len(),
+        NftMarketplaceError::InvalidCreator
+    );
+    for ((creator, amount), creator_account) in royalties.iter().zip(creator_accounts) {
+        let receiver = match payer.payment_mint {
+            Some(_) => {
+                let data = creator_account.try_borrow_data()?;
+                StateWithExtensions::<spl_token_2022::state::Account>::unpack(&data)?
+                    .base
+                    .owner
+            }
+            None => creator_account.key(),
+        };
+        require_keys_eq!(receiver, *creator, NftMarketplaceError::InvalidCreator);
+        payer.pay(creator_account.clone(), *amount)?;
+    }
+    Ok(())
+}
+
+// Creates the associated token account of `authority` for `mint` unless it already exists
+fn create_token_account<'info>(
+    associated_token_program: &Program<'info, AssociatedToken>,
+    payer: AccountInfo<'info>,
+    token_account: AccountInfo<'info>,
+    authority: AccountInfo<'info>,
+    mint: AccountInfo<'info>,
+    system_program: &Program<'info, System>,
+    token_program: &Interface<'info, TokenInterface>,
+) -> Result<()> {
+    anchor_spl::associated_token::create_idempotent(CpiContext::new(
+        associated_token_program.to_account_info(),
+        anchor_spl::associated_token::Create {
+            payer,
+            associated_token: token_account,
+            authority,
+            mint,
+            system_program: system_program.to_account_info(),
+            token_program: token_program.to_account_info(),
+        },
+    ))
+}
+
+// Moves one NFT out of a token account owned by an escrow PDA and closes it,
+// returning its rent to `receiver`
+fn release_escrowed_nft<'info>(
+    token_program: &Interface<'info, TokenInterface>,
+    mint: &'info AccountInfo<'info>,
+    from: AccountInfo<'info>,
+    to: AccountInfo<'info>,
+    escrow: AccountInfo<'info>,
+    receiver: AccountInfo<'info>,
+    signer_seeds: &[&[&[u8]]],
+) -> Result<()> {
+    transfer_nft(
+        token_program.to_account_info(),
+        from.clone(),
+        &InterfaceAccount::<Mint>::try_from(mint)?,
+        to,
+        escrow.clone(),
+        &[],
+        signer_seeds,
+    )?;
+    anchor_spl::token_interface::close_account(CpiContext::new_with_signer(
+        token_program.to_account_info(),
+        anchor_spl::token_interface::CloseAccount {
+            account: from,
+            destination: receiver,
+            authority: escrow,
+        },
+        signer_seeds,
+    ))
+}
+
+// Passes the merkle proof nodes in `remaining_accounts` through to Bubblegum
+fn proof_accounts<'a, 'info>(
+    remaining_accounts: &'a [AccountInfo<'info>],
+) -> Vec<(&'a AccountInfo<'info>, bool, bool)> {
+    remaining_accounts
+        .iter()
+        .map(|node| (node, false, false))
+        .collect()
+}
+
+// Accounts passed through to Token Metadata's Transfer instruction
+pub struct ProgrammableTransfer<'info> {
+    pub token: AccountInfo<'info>,
+    pub token_owner: AccountInfo<'info>,
+    pub destination: AccountInfo<'info>,
+    pub destination_owner: AccountInfo<'info>,
+    pub mint: AccountInfo<'info>,
+    pub metadata: AccountInfo<'info>,
+    pub edition: AccountInfo<'info>,
+    pub owner_token_record: AccountInfo<'info>,
+    pub destination_token_record: AccountInfo<'info>,
+    pub authority: AccountInfo<'info>,
+    pub payer: AccountInfo<'info>,
+    pub system_program: AccountInfo<'info>,
+    pub sysvar_instructions: AccountInfo<'info>,
+    pub token_program: AccountInfo<'info>,
+    pub associated_token_program: AccountInfo<'info>,
+    pub token_metadata_program: AccountInfo<'info>,
+    pub authorization_rules_program: Option<AccountInfo<'info>>,
+    pub authorization_rules: Option<AccountInfo<'info>>,
+}
+
+// Moves a single programmable NFT with Token Metadata's Transfer instruction
+pub fn transfer_programmable_nft(
+    accounts: ProgrammableTransfer,
+    signer_seeds: &[&[&[u8]]],
+) -> Result<()> {
+    let instruction = builders::Transfer {
+        token: accounts.token.key(),
+        token_owner: accounts.token_owner.key(),
+        destination: accounts.destination.key(),
+        destination_owner: accounts.destination_owner.key(),
+        mint: accounts.mint.key(),
+        metadata: accounts.metadata.key(),
+        edition: Some(accounts.edition.key()),
+        owner_token_record: Some(accounts.owner_token_record.key()),
+        destination_token_record: Some(accounts.destination_token_record.key()),
+        authority: accounts.authority.key(),
+        payer: accounts.payer.key(),
+        system_program: accounts.system_program.key(),
+        sysvar_instructions: accounts.sysvar_instructions.key(),
+        spl_token_program: accounts.token_program.key(),
+        spl_ata_program: accounts.associated_token_program.key(),
+        authorization_rules_program: accounts
+            .authorization_rules_program
+            .as_ref()
+            .map(|program| program.key()),
+        authorization_rules: accounts.authorization_rules.as_ref().map(|rules| rules.key()),
+        args: TransferArgs::V1 {
+            amount: 1,
+            authorization_data: None,
+        },
+    }
+    .instruction();
+
+    let mut account_infos = vec![
+        accounts.token,
+        accounts.token_owner,
+        accounts.destination,
+        accounts.destination_owner,
+        accounts.mint,
+        accounts.metadata,
+        accounts.edition,
+        accounts.owner_token_record,
+        accounts.destination_token_record,
+        accounts.authority,
+        accounts.payer,
+        accounts.system_program,
+        accounts.sysvar_instructions,
+        accounts.token_program,
+        accounts.associated_token_program,
+        accounts.token_metadata_program,
+    ];
+    account_infos.extend(accounts.authorization_rules_program);
+    account_infos.extend(accounts.authorization_rules);
+
+    invoke_signed(&instruction, &account_infos, signer_seeds)?;
+    Ok(())
+}
+
+// Unwraps an optional account that the programmable NFT path requires
+fn required_account<'info, T: ToAccountInfo<'info>>(account: &Option<T>) -> Result<AccountInfo<'info>> {
+    account
+        .as_ref()
+        .map(|account| account.to_account_info())
+        .ok_or_else(|| error!(NftMarketplaceError::MissingProgrammableAccount))
+}
+
+// Unwraps an optional account required by the listing's mode
+fn listing_account<'info, T: ToAccountInfo<'info>>(account: &Option<T>) -> Result<AccountInfo<'info>> {
+    account
+        .as_ref()
+        .map(|account| account.to_account_info())
+        .ok_or_else(|| error!(NftMarketplaceError::MissingListingAccount))
+}
+
+// Passes through an optional account such as the authorization rules
+fn optional_account<'info, T: ToAccountInfo<'info>>(account: &Option<T>) -> Option<AccountInfo<'info>> {
+    account.as_ref().map(|account| account.to_account_info())
+}
+
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct InitializeMarketplace<'info> {
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + 32 + 8 + 8 + 8 + 32 + 1 + 4 + 32 * MAX_PAYMENT_MINTS + 1 + 32 + 32 + 4 + MAX_MARKETPLACE_NAME_LEN + 8,
+        seeds = [b"marketplace", authority.key().as_ref(), name.as_bytes()],
+        bump
+    )]
+    pub marketplace: Account<'info, Marketplace>,
+    
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    
+    /// CHECK: This is the treasury account that will receive fees
+    pub treasury: AccountInfo<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateMarketplace<'info> {
+    #[account(
+        mut,
+        seeds = [b"marketplace", marketplace.creator.as_ref(), marketplace.name.as_bytes()],
+        bump = marketplace.bump,
+        has_one = authority
+    )]
+    pub marketplace: Account<'info, Marketplace>,
+    
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptAuthority<'info> {
+    #[account(
+        mut,
+        seeds = [b"marketplace", marketplace.creator.as_ref(), marketplace.name.as_bytes()],
+        bump = marketplace.bump,
+        constraint = marketplace.pending_authority == Some(new_authority.key())
+            @ NftMarketplaceError::NotPendingAuthority
+    )]
+    pub marketplace: Account<'info, Marketplace>,
+    
+    pub new_authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(wallet: Pubkey)]
+pub struct RegisterReferrer<'info> {
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + 32 + 32 + 1,
+        seeds = [b"referrer", marketplace.key().as_ref(), wallet.as_ref()],
+        bump
+    )]
+    pub referrer: Account<'info, Referrer>,
+    
+    #[account(
+        seeds = [b"marketplace", marketplace.creator.as_ref(), marketplace.name.as_bytes()],
+        bump = marketplace.bump,
+        has_one = authority
+    )]
+    pub marketplace: Account<'info, Marketplace>,
+    
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RemoveReferrer<'info> {
+    #[account(
+        mut,
+        seeds = [b"referrer", marketplace.key().as_ref(), referrer.wallet.as_ref()],
+        bump = referrer.bump,
+        has_one = marketplace,
+        close = authority
+    )]
+    pub referrer: Account<'info, Referrer>,
+    
+    #[account(
+        seeds = [b"marketplace", marketplace.creator.as_ref(), marketplace.name.as_bytes()],
+        bump = marketplace.bump,
+        has_one = authority
+    )]
+    pub marketplace: Account<'info, Marketplace>,
+    
+    #[account(mut)]
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(price: u64, auction_house_fee: u64)]
+pub struct ListNft<'info> {
+    #[account(
+        init,
+        payer = seller,
+        space = 8 + 32 + 32 + 32 + 8 + 1 + 8 + 1 + 1 + 1 + 1 + 1 + 1 + 32 + 1 + 8 + 8 + 8 + 8 + 1 + 32 + 8 + 8,
+        seeds = [b"listing", marketplace.key().as_ref(), mint.key().as_ref(), seller.key().as_ref()],
+        bump
+    )]
+    pub listing: Account<'info, Listing>,
+    
+    #[account(
+        seeds = [b"marketplace", marketplace.creator.as_ref(), marketplace.name.as_bytes()],
+        bump = marketplace.bump
+    )]
+    pub marketplace: Account<'info, Marketplace>,
+    
+    #[account(mut)]
+    pub seller: Signer<'info>,
+    
+    pub mint: InterfaceAccount<'info, Mint>,
+    
+    /// Mint the order is priced in; omitted for SOL
+    pub payment_mint: Option<InterfaceAccount<'info, Mint>>,
+    
+    #[account(
+        mut,
+        constraint = seller_token_account.owner == seller.key(),
+        constraint = seller_token_account.mint == mint.key()
+    )]
+    pub seller_token_account: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        init,
+        payer = seller,
+        seeds = [b"vault", listing.key().as_ref()],
+        bump,
+        token::mint = mint,
+        token::authority = vault,
+        token::token_program = token_program
+    )]
+    pub vault: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    #[account(
+        mut,
+        seeds = [b"delegate", listing.key().as_ref()],
+        bump
+    )]
+    /// CHECK: PDA approved as delegate of the seller's token account
+    pub delegate: Option<UncheckedAccount<'info>>,
+    
+    /// CHECK: Master edition of the mint, validated by Token Metadata
+    pub edition: Option<UncheckedAccount<'info>>,
+    
+    #[account(address = mpl_token_metadata::ID)]
+    /// CHECK: This is the Token Metadata program
+    pub token_metadata_program: Option<UncheckedAccount<'info>>,
+    
+    #[account(mut)]
+    /// CHECK: Token record of the source token account, validated by Token Metadata
+    pub owner_token_record: Option<UncheckedAccount<'info>>,
+    
+    #[account(mut)]
+    /// CHECK: Token record of the destination token account, validated by Token Metadata
+    pub destination_token_record: Option<UncheckedAccount<'info>>,
+    
+    /// CHECK: Token Authorization Rules program, validated by Token Metadata
+    pub authorization_rules_program: Option<UncheckedAccount<'info>>,
+    
+    /// CHECK: Authorization rule set of the mint, validated by Token Metadata
+    pub authorization_rules: Option<UncheckedAccount<'info>>,
+    
+    #[account(address = anchor_lang::solana_program::sysvar::instructions::ID)]
+    /// CHECK: This is the instructions sysvar
+    pub sysvar_instructions: Option<UncheckedAccount<'info>>,
+    
+    #[account(
+        mut,
+        seeds = [b"metadata", mpl_token_metadata::ID.as_ref(), mint.key().as_ref()],
+        seeds::program = mpl_token_metadata::ID,
+        bump
+    )]
+    /// CHECK: Metadata PDA of the mint, deserialized by the instruction
+    pub metadata: UncheckedAccount<'info>,
+    
+    /// CHECK: Config PDA of the NFT's verified collection, validated by the instruction
+    pub collection_config: Option<UncheckedAccount<'info>>,
+    
+    pub associated_token_program: Option<Program<'info, AssociatedToken>>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateListing<'info> {
+    #[account(
+        mut,
+        seeds = [b"listing", listing.marketplace.as_ref(), listing.mint.as_ref(), seller.key().as_ref()],
+        bump = listing.bump,
+        has_one = marketplace,
+        constraint = listing.seller == seller.key(),
+        constraint = listing.active
+    )]
+    pub listing: Account<'info, Listing>,
+    
+    #[account(
+        seeds = [b"marketplace", marketplace.creator.as_ref(), marketplace.name.as_bytes()],
+        bump = marketplace.bump
+    )]
+    pub marketplace: Account<'info, Marketplace>,
+    
+    pub seller: Signer<'info>,
+    
+    /// Mint the listing is priced in; omitted for SOL
+    pub payment_mint: Option<InterfaceAccount<'info, Mint>>,
+    
+    #[account(
+        seeds = [b"metadata", mpl_token_metadata::ID.as_ref(), listing.mint.as_ref()],
+        seeds::program = mpl_token_metadata::ID,
+        bump
+    )]
+    /// CHECK: Metadata PDA of the mint, deserialized by the instruction
+    pub metadata: UncheckedAccount<'info>,
+    
+    /// CHECK: Config PDA of the NFT's verified collection, validated by the instruction
+    pub collection_config: Option<UncheckedAccount<'info>>,
+}
+
+#[derive(Accounts)]
+pub struct CancelListing<'info> {
+    #[account(
+        mut,
+        seeds = [b"listing", listing.marketplace.as_ref(), listing.mint.as_ref(), seller.key().as_ref()],
+        bump = listing.bump,
+        close = seller,
+        constraint = listing.seller == seller.key(),
+        constraint = listing.active
+    )]
+    pub listing: Account<'info, Listing>,
+    
+    #[account(mut)]
+    pub seller: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = seller_token_account.owner == seller.key(),
+        constraint = seller_token_account.mint == listing.mint
+    )]
+    pub seller_token_account: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        seeds = [b"vault", listing.key().as_ref()],
+        bump = listing.vault_bump
+    )]
+    pub vault: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    #[account(
+        mut,
+        seeds = [b"delegate", listing.key().as_ref()],
+        bump = listing.delegate_bump
+    )]
+    /// CHECK: PDA approved as delegate of the seller's token account
+    pub delegate: Option<UncheckedAccount<'info>>,
+    
+    /// CHECK: Master edition of the mint, validated by Token Metadata
+    pub edition: Option<UncheckedAccount<'info>>,
+    
+    #[account(address = listing.mint)]
+    pub mint: InterfaceAccount<'info, Mint>,
+    
+    #[account(address = mpl_token_metadata::ID)]
+    /// CHECK: This is the Token Metadata program
+    pub token_metadata_program: Option<UncheckedAccount<'info>>,
+    
+    #[account(mut)]
+    /// CHECK: Token record of the source token account, validated by Token Metadata
+    pub owner_token_record: Option<UncheckedAccount<'info>>,
+    
+    #[account(mut)]
+    /// CHECK: Token record of the destination token account, validated by Token Metadata
+    pub destination_token_record: Option<UncheckedAccount<'info>>,
+    
+    /// CHECK: Token Authorization Rules program, validated by Token Metadata
+    pub authorization_rules_program: Option<UncheckedAccount<'info>>,
+    
+    /// CHECK: Authorization rule set of the mint, validated by Token Metadata
+    pub authorization_rules: Option<UncheckedAccount<'info>>,
+    
+    #[account(address = anchor_lang::solana_program::sysvar::instructions::ID)]
+    /// CHECK: This is the instructions sysvar
+    pub sysvar_instructions: Option<UncheckedAccount<'info>>,
+    
+    #[account(
+        mut,
+        seeds = [b"metadata", mpl_token_metadata::ID.as_ref(), listing.mint.as_ref()],
+        seeds::program = mpl_token_metadata::ID,
+        bump
+    )]
+    /// CHECK: Metadata PDA of the mint, deserialized by the instruction
+    pub metadata: UncheckedAccount<'info>,
+    
+    pub associated_token_program: Option<Program<'info, AssociatedToken>>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ExpireListing<'info> {
+    #[account(
+        mut,
+        seeds = [b"listing", listing.marketplace.as_ref(), listing.mint.as_ref(), seller.key().as_ref()],
+        bump = listing.bump,
+        close = seller,
+        constraint = listing.seller == seller.key(),
+        constraint = listing.active
+    )]
+    pub listing: Account<'info, Listing>,
+    
+    #[account(mut)]
+    /// CHECK: This is the seller account
+    pub seller: AccountInfo<'info>,
+    
+    #[account(mut)]
+    pub cranker: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = seller_token_account.owner == seller.key(),
+        constraint = seller_token_account.mint == listing.mint
+    )]
+    pub seller_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    #[account(
+        mut,
+        seeds = [b"vault", listing.key().as_ref()],
+        bump = listing.vault_bump
+    )]
+    pub vault: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    #[account(
+        mut,
+        seeds = [b"delegate", listing.key().as_ref()],
+        bump = listing.delegate_bump
+    )]
+    /// CHECK: PDA approved as delegate of the seller's token account, or of the compressed leaf
+    pub delegate: Option<UncheckedAccount<'info>>,
+    
+    /// CHECK: Master edition of the mint, validated by Token Metadata
+    pub edition: Option<UncheckedAccount<'info>>,
+    
+    #[account(address = listing.mint)]
+    pub mint: Option<InterfaceAccount<'info, Mint>>,
+    
+    #[account(address = mpl_token_metadata::ID)]
+    /// CHECK: This is the Token Metadata program
+    pub token_metadata_program: Option<UncheckedAccount<'info>>,
+    
+    #[account(mut)]
+    /// CHECK: Token record of the source token account, validated by Token Metadata
+    pub owner_token_record: Option<UncheckedAccount<'info>>,
+    
+    #[account(mut)]
+    /// CHECK: Token record of the destination token account, validated by Token Metadata
+    pub destination_token_record: Option<UncheckedAccount<'info>>,
+    
+    /// CHECK: Token Authorization Rules program, validated by Token Metadata
+    pub authorization_rules_program: Option<UncheckedAccount<'info>>,
+    
+    /// CHECK: Authorization rule set of the mint, validated by Token Metadata
+    pub authorization_rules: Option<UncheckedAccount<'info>>,
+    
+    #[account(address = anchor_lang::solana_program::sysvar::instructions::ID)]
+    /// CHECK: This is the instructions sysvar
+    pub sysvar_instructions: Option<UncheckedAccount<'info>>,
+    
+    #[account(
+        mut,
+        seeds = [b"metadata", mpl_token_metadata::ID.as_ref(), listing.mint.as_ref()],
+        seeds::program = mpl_token_metadata::ID,
+        bump
+    )]
+    /// CHECK: Metadata PDA of the mint, deserialized by the instruction
+    pub metadata: UncheckedAccount<'info>,
+    
+    /// CHECK: Bubblegum tree config PDA, validated by Bubblegum
+    pub tree_config: Option<UncheckedAccount<'info>>,
+    
+    #[account(mut)]
+    /// CHECK: Concurrent merkle tree holding the leaf, validated by Bubblegum
+    pub merkle_tree: Option<UncheckedAccount<'info>>,
+    
+    #[account(address = mpl_bubblegum::programs::SPL_NOOP_ID)]
+    /// CHECK: This is the SPL Noop program
+    pub log_wrapper: Option<UncheckedAccount<'info>>,
+    
+    #[account(address = mpl_bubblegum::programs::SPL_ACCOUNT_COMPRESSION_ID)]
+    /// CHECK: This is the SPL Account Compression program
+    pub compression_program: Option<UncheckedAccount<'info>>,
+    
+    #[account(address = mpl_bubblegum::ID)]
+    /// CHECK: This is the Bubblegum program
+    pub bubblegum_program: Option<UncheckedAccount<'info>>,
+    
+    pub associated_token_program: Option<Program<'info, AssociatedToken>>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct BuyNft<'info> {
+    #[account(
+        seeds = [b"marketplace", marketplace.creator.as_ref(), marketplace.name.as_bytes()],
+        bump = marketplace.bump,
+        has_one = treasury
+    )]
+    pub marketplace: Account<'info, Marketplace>,
+    
+    #[account(
+        mut,
+        seeds = [b"listing", listing.marketplace.as_ref(), listing.mint.as_ref(), seller.key().as_ref()],
+        bump = listing.bump,
+        has_one = marketplace,
+        constraint = listing.active
+    )]
+    pub listing: Account<'info, Listing>,
+    
+    #[account(mut)]
+    pub buyer: Signer<'info>,
+    
+    #[account(mut)]
+    /// CHECK: This is the seller account
+    pub seller: AccountInfo<'info>,
+    
+    #[account(mut)]
+    /// CHECK: This is the treasury account
+    pub treasury: AccountInfo<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"vault", listing.key().as_ref()],
+        bump = listing.vault_bump,
+        constraint = vault.amount >= listing.quantity
+    )]
+    pub vault: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    #[account(
+        mut,
+        constraint = seller_token_account.owner == seller.key(),
+        constraint = seller_token_account.mint == listing.mint,
+        constraint = seller_token_account.amount >= listing.quantity
+    )]
+    pub seller_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    #[account(
+        mut,
+        seeds = [b"delegate", listing.key().as_ref()],
+        bump = listing.delegate_bump
+    )]
+    /// CHECK: PDA approved as delegate of the seller's token account
+    pub delegate: Option<UncheckedAccount<'info>>,
+    
+    /// CHECK: Master edition of the mint, validated by Token Metadata
+    pub edition: Option<UncheckedAccount<'info>>,
+    
+    #[account(address = mpl_token_metadata::ID)]
+    /// CHECK: This is the Token Metadata program
+    pub token_metadata_program: Option<UncheckedAccount<'info>>,
+    
+    #[account(
+        init_if_needed,
+        payer = buyer,
+        associated_token::mint = mint,
+        associated_token::authority = buyer,
+        associated_token::token_program = token_program
+    )]
+    pub buyer_token_account: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        seeds = [b"metadata", mpl_token_metadata::ID.as_ref(), listing.mint.as_ref()],
+        seeds::program = mpl_token_metadata::ID,
+        bump
+    )]
+    /// CHECK: Metadata PDA of the mint, deserialized by the instruction
+    pub metadata: UncheckedAccount<'info>,
+    
+    /// CHECK: Config PDA of the NFT's verified collection, validated by the instruction
+    pub collection_config: Option<UncheckedAccount<'info>>,
+    
+    #[account(address = listing.mint)]
+    pub mint: InterfaceAccount<'info, Mint>,
+
+    /// Mint the order is priced in; omitted for SOL
+    pub payment_mint: Option<InterfaceAccount<'info, Mint>>,
+    
+    #[account(
+        mut,
+        constraint = buyer_payment_account.owner == buyer.key()
+    )]
+    pub buyer_payment_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    #[account(
+        mut,
+        constraint = seller_payment_account.owner == seller.key()
+    )]
+    pub seller_payment_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    #[account(
+        mut,
+        constraint = treasury_payment_account.owner == marketplace.treasury
+    )]
+    pub treasury_payment_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    #[account(mut)]
+    /// CHECK: Wallet of the front-end or aggregator routing the trade
+    pub referrer: Option<UncheckedAccount<'info>>,
+    
+    /// Registration of `referrer` on the marketplace, required to pay it
+    pub registered_referrer: Option<Account<'info, Referrer>>,
+    
+    #[account(mut)]
+    pub referrer_payment_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    pub payment_token_program: Option<Interface<'info, TokenInterface>>,
+    
+    #[account(mut)]
+    /// CHECK: Token record of the source token account, validated by Token Metadata
+    pub owner_token_record: Option<UncheckedAccount<'info>>,
+    
+    #[account(mut)]
+    /// CHECK: Token record of the destination token account, validated by Token Metadata
+    pub destination_token_record: Option<UncheckedAccount<'info>>,
+    
+    /// CHECK: Token Authorization Rules program, validated by Token Metadata
+    pub authorization_rules_program: Option<UncheckedAccount<'info>>,
+    
+    /// CHECK: Authorization rule set of the mint, validated by Token Metadata
+    pub authorization_rules: Option<UncheckedAccount<'info>>,
+    
+    #[account(address = anchor_lang::solana_program::sysvar::instructions::ID)]
+    /// CHECK: This is the instructions sysvar
+    pub sysvar_instructions: Option<UncheckedAccount<'info>>,
+    
+    pub token_program: Interface<'info, TokenInterface>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+#[instruction(price: u64, asset_id: Pubkey)]
+pub struct ListCompressedNft<'info> {
+    #[account(
+        init,
+        payer = seller,
+        space = 8 + 32 + 32 + 32 + 8 + 1 + 8 + 1 + 1 + 1 + 1 + 1 + 1 + 32 + 1 + 8 + 8 + 8 + 8 + 1 + 32 + 8 + 8,
+        seeds = [b"listing", marketplace.key().as_ref(), asset_id.as_ref(), seller.key().as_ref()],
+        bump
+    )]
+    pub listing: Account<'info, Listing>,
+    
+    #[account(
+        seeds = [b"marketplace", marketplace.creator.as_ref(), marketplace.name.as_bytes()],
+        bump = marketplace.bump
+    )]
+    pub marketplace: Account<'info, Marketplace>,
+    
+    #[account(mut)]
+    pub seller: Signer<'info>,
+    
+    /// Mint the order is priced in; omitted for SOL
+    pub payment_mint: Option<InterfaceAccount<'info, Mint>>,
+    
+    /// CHECK: Config PDA of the NFT's verified collection, validated by the instruction
+    pub collection_config: Option<UncheckedAccount<'info>>,
+    
+    /// CHECK: Current delegate of the leaf, validated by Bubblegum
+    pub previous_leaf_delegate: UncheckedAccount<'info>,
+    
+    #[account(
+        seeds = [b"delegate", listing.key().as_ref()],
+        bump
+    )]
+    /// CHECK: PDA set as the leaf delegate while listed
+    pub delegate: UncheckedAccount<'info>,
+    
+    /// CHECK: Bubblegum tree config PDA, validated by Bubblegum
+    pub tree_config: UncheckedAccount<'info>,
+    
+    #[account(mut)]
+    /// CHECK: Concurrent merkle tree holding the leaf, validated by Bubblegum
+    pub merkle_tree: UncheckedAccount<'info>,
+    
+    #[account(address = mpl_bubblegum::programs::SPL_NOOP_ID)]
+    /// CHECK: This is the SPL Noop program
+    pub log_wrapper: UncheckedAccount<'info>,
+    
+    #[account(address = mpl_bubblegum::programs::SPL_ACCOUNT_COMPRESSION_ID)]
+    /// CHECK: This is the SPL Account Compression program
+    pub compression_program: UncheckedAccount<'info>,
+    
+    #[account(address = mpl_bubblegum::ID)]
+    /// CHECK: This is the Bubblegum program
+    pub bubblegum_program: UncheckedAccount<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CancelCompressedListing<'info> {
+    #[account(
+        mut,
+        seeds = [b"listing", listing.marketplace.as_ref(), listing.mint.as_ref(), seller.key().as_ref()],
+        bump = listing.bump,
+        close = seller,
+        constraint = listing.seller == seller.key(),
+        constraint = listing.mode == ListingMode::Compressed,
+        constraint = listing.active
+    )]
+    pub listing: Account<'info, Listing>,
+    
+    #[account(mut)]
+    pub seller: Signer<'info>,
+    
+    #[account(
+        seeds = [b"delegate", listing.key().as_ref()],
+        bump = listing.delegate_bump
+    )]
+    /// CHECK: PDA set as the leaf delegate while listed
+    pub delegate: UncheckedAccount<'info>,
+    
+    /// CHECK: Bubblegum tree config PDA, validated by Bubblegum
+    pub tree_config: UncheckedAccount<'info>,
+    
+    #[account(mut)]
+    /// CHECK: Concurrent merkle tree holding the leaf, validated by Bubblegum
+    pub merkle_tree: UncheckedAccount<'info>,
+    
+    #[account(address = mpl_bubblegum::programs::SPL_NOOP_ID)]
+    /// CHECK: This is the SPL Noop program
+    pub log_wrapper: UncheckedAccount<'info>,
+    
+    #[account(address = mpl_bubblegum::programs::SPL_ACCOUNT_COMPRESSION_ID)]
+    /// CHECK: This is the SPL Account Compression program
+    pub compression_program: UncheckedAccount<'info>,
+    
+    #[account(address = mpl_bubblegum::ID)]
+    /// CHECK: This is the Bubblegum program
+    pub bubblegum_program: UncheckedAccount<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct BuyCompressedNft<'info> {
+    #[account(
+        seeds = [b"marketplace", marketplace.creator.as_ref(), marketplace.name.as_bytes()],
+        bump = marketplace.bump,
+        has_one = treasury
+    )]
+    pub marketplace: Account<'info, Marketplace>,
+    
+    #[account(
+        mut,
+        seeds = [b"listing", listing.marketplace.as_ref(), listing.mint.as_ref(), seller.key().as_ref()],
+        bump = listing.bump,
+        has_one = marketplace,
+        close = seller,
+        constraint = listing.mode == ListingMode::Compressed,
+        constraint = listing.active
+    )]
+    pub listing: Account<'info, Listing>,
+    
+    #[account(mut)]
+    pub buyer: Signer<'info>,
+    
+    #[account(mut)]
+    /// CHECK: This is the seller account
+    pub seller: AccountInfo<'info>,
+    
+    #[account(mut)]
+    /// CHECK: This is the treasury account
+    pub treasury: AccountInfo<'info>,
+
+    /// Mint the order is priced in; omitted for SOL
+    pub payment_mint: Option<InterfaceAccount<'info, Mint>>,
+    
+    #[account(
+        mut,
+        constraint = buyer_payment_account.owner == buyer.key()
+    )]
+    pub buyer_payment_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    #[account(
+        mut,
+        constraint = seller_payment_account.owner == seller.key()
+    )]
+    pub seller_payment_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    #[account(
+        mut,
+        constraint = treasury_payment_account.owner == marketplace.treasury
+    )]
+    pub treasury_payment_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    #[account(mut)]
+    /// CHECK: Wallet of the front-end or aggregator routing the trade
+    pub referrer: Option<UncheckedAccount<'info>>,
+    
+    /// Registration of `referrer` on the marketplace, required to pay it
+    pub registered_referrer: Option<Account<'info, Referrer>>,
+    
+    #[account(mut)]
+    pub referrer_payment_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    pub payment_token_program: Option<Interface<'info, TokenInterface>>,
+    
+    /// CHECK: Config PDA of the NFT's verified collection, validated by the instruction
+    pub collection_config: Option<UncheckedAccount<'info>>,
+    
+    #[account(
+        seeds = [b"delegate", listing.key().as_ref()],
+        bump = listing.delegate_bump
+    )]
+    /// CHECK: PDA set as the leaf delegate while listed
+    pub delegate: UncheckedAccount<'info>,
+    
+    /// CHECK: Bubblegum tree config PDA, validated by Bubblegum
+    pub tree_config: UncheckedAccount<'info>,
+    
+    #[account(mut)]
+    /// CHECK: Concurrent merkle tree holding the leaf, validated by Bubblegum
+    pub merkle_tree: UncheckedAccount<'info>,
+    
+    #[account(address = mpl_bubblegum::programs::SPL_NOOP_ID)]
+    /// CHECK: This is the SPL Noop program
+    pub log_wrapper: UncheckedAccount<'info>,
+    
+    #[account(address = mpl_bubblegum::programs::SPL_ACCOUNT_COMPRESSION_ID)]
+    /// CHECK: This is the SPL Account Compression program
+    pub compression_program: UncheckedAccount<'info>,
+    
+    #[account(address = mpl_bubblegum::ID)]
+    /// CHECK: This is the Bubblegum program
+    pub bubblegum_program: UncheckedAccount<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(price: u64, expiry_time: i64)]
+pub struct MakeOffer<'info> {
+    #[account(
+        init,
+        payer = buyer,
+        space = 8 + 32 + 32 + 32 + 8 + 8 + 8 + 1 + 1 + 1 + 32 + 1 + 8,
+        seeds = [b"offer", marketplace.key().as_ref(), mint.key().as_ref(), buyer.key().as_ref()],
+        bump
+    )]
+    pub offer: Account<'info, Offer>,
+    
+    #[account(
+        init,
+        payer = buyer,
+        space = 0,
+        seeds = [b"escrow", marketplace.key().as_ref(), mint.key().as_ref(), buyer.key().as_ref()],
+        bump
+    )]
+    /// CHECK: Program-owned PDA holding the lamports of a SOL offer
+    pub escrow: Option<UncheckedAccount<'info>>,
+    
+    #[account(mut)]
+    pub buyer: Signer<'info>,
+    
+    pub mint: InterfaceAccount<'info, Mint>,
+    
+    #[account(
+        seeds = [b"metadata", mpl_token_metadata::ID.as_ref(), mint.key().as_ref()],
+        seeds::program = mpl_token_metadata::ID,
+        bump
+    )]
+    /// CHECK: Metadata PDA of the mint, deserialized by the instruction
+    pub metadata: UncheckedAccount<'info>,
+    
+    /// CHECK: Config PDA of the NFT's verified collection, validated by the instruction
+    pub collection_config: Option<UncheckedAccount<'info>>,
+    
+    #[account(
+        seeds = [b"marketplace", marketplace.creator.as_ref(), marketplace.name.as_bytes()],
+        bump = marketplace.bump
+    )]
+    pub marketplace: Account<'info, Marketplace>,
+    
+    /// Mint the order is priced in; omitted for SOL
+    pub payment_mint: Option<InterfaceAccount<'info, Mint>>,
+    
+    #[account(
+        mut,
+        constraint = buyer_payment_account.owner == buyer.key()
+    )]
+    pub buyer_payment_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    #[account(
+        init,
+        payer = buyer,
+        seeds = [b"payment_escrow", offer.key().as_ref()],
+        bump,
+        token::mint = payment_mint,
+        token::authority = offer,
+        token::token_program = payment_token_program
+    )]
+    pub payment_escrow: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    pub payment_token_program: Option<Interface<'info, TokenInterface>>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptOffer<'info> {
+    #[account(
+        seeds = [b"marketplace", marketplace.creator.as_ref(), marketplace.name.as_bytes()],
+        bump = marketplace.bump,
+        has_one = treasury
+    )]
+    pub marketplace: Account<'info, Marketplace>,
+    
+    #[account(
+        mut,
+        seeds = [b"offer", offer.marketplace.as_ref(), offer.mint.as_ref(), offer.buyer.as_ref()],
+        bump = offer.bump,
+        has_one = marketplace,
+        close = buyer,
+        constraint = offer.active
+    )]
+    pub offer: Account<'info, Offer>,
+    
+    #[account(
+        mut,
+        seeds = [b"escrow", offer.marketplace.as_ref(), offer.mint.as_ref(), offer.buyer.as_ref()],
+        bump = offer.escrow_bump
+    )]
+    /// CHECK: Program-owned PDA holding the lamports of a SOL offer
+    pub escrow: Option<UncheckedAccount<'info>>,
+    
+    #[account(mut)]
+    pub seller: Signer<'info>,
+    
+    #[account(mut, address = offer.buyer)]
+    /// CHECK: This is the buyer account
+    pub buyer: AccountInfo<'info>,
+    
+    #[account(mut)]
+    /// CHECK: This is the treasury account
+    pub treasury: AccountInfo<'info>,
+    
+    /// Mint the order is priced in; omitted for SOL
+    pub payment_mint: Option<InterfaceAccount<'info, Mint>>,
+    
+    #[account(
+        mut,
+        seeds = [b"payment_escrow", offer.key().as_ref()],
+        bump
+    )]
+    pub payment_escrow: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    #[account(
+        mut,
+        constraint = seller_payment_account.owner == seller.key()
+    )]
+    pub seller_payment_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    #[account(
+        mut,
+        constraint = treasury_payment_account.owner == marketplace.treasury
+    )]
+    pub treasury_payment_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    #[account(mut)]
+    /// CHECK: Wallet of the front-end or aggregator routing the trade
+    pub referrer: Option<UncheckedAccount<'info>>,
+    
+    /// Registration of `referrer` on the marketplace, required to pay it
+    pub registered_referrer: Option<Account<'info, Referrer>>,
+    
+    #[account(mut)]
+    pub referrer_payment_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    pub payment_token_program: Option<Interface<'info, TokenInterface>>,
+    
+    #[account(
+        mut,
+        constraint = seller_token_account.owner == seller.key(),
         constraint = seller_token_account.mint == offer.mint,
         constraint = seller_token_account.amount == 1
     )]
-    pub seller_token_account: Account<'info, TokenAccount>,
+    pub seller_token_account: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        constraint = buyer_token_account.owner == buyer.key(),
+        constraint = buyer_token_account.mint == offer.mint
+    )]
+    pub buyer_token_account: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        seeds = [b"metadata", mpl_token_metadata::ID.as_ref(), offer.mint.as_ref()],
+        seeds::program = mpl_token_metadata::ID,
+        bump
+    )]
+    /// CHECK: Metadata PDA of the mint, deserialized by the instruction
+    pub metadata: UncheckedAccount<'info>,
+    
+    /// CHECK: Config PDA of the NFT's verified collection, validated by the instruction
+    pub collection_config: Option<UncheckedAccount<'info>>,
+    
+    /// CHECK: Master edition of the mint, validated by Token Metadata
+    pub edition: Option<UncheckedAccount<'info>>,
+    
+    #[account(mut)]
+    /// CHECK: Token record of the source token account, validated by Token Metadata
+    pub owner_token_record: Option<UncheckedAccount<'info>>,
+    
+    #[account(mut)]
+    /// CHECK: Token record of the destination token account, validated by Token Metadata
+    pub destination_token_record: Option<UncheckedAccount<'info>>,
+    
+    /// CHECK: Token Authorization Rules program, validated by Token Metadata
+    pub authorization_rules_program: Option<UncheckedAccount<'info>>,
+    
+    /// CHECK: Authorization rule set of the mint, validated by Token Metadata
+    pub authorization_rules: Option<UncheckedAccount<'info>>,
+    
+    #[account(address = anchor_lang::solana_program::sysvar::instructions::ID)]
+    /// CHECK: This is the instructions sysvar
+    pub sysvar_instructions: Option<UncheckedAccount<'info>>,
+    
+    #[account(address = mpl_token_metadata::ID)]
+    /// CHECK: This is the Token Metadata program
+    pub token_metadata_program: Option<UncheckedAccount<'info>>,
+    
+    #[account(address = offer.mint)]
+    pub mint: InterfaceAccount<'info, Mint>,
+    
+    pub token_program: Interface<'info, TokenInterface>,
+    pub associated_token_program: Option<Program<'info, AssociatedToken>>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CancelOffer<'info> {
+    #[account(
+        mut,
+        seeds = [b"offer", offer.marketplace.as_ref(), offer.mint.as_ref(), buyer.key().as_ref()],
+        bump = offer.bump,
+        close = buyer,
+        constraint = offer.buyer == buyer.key(),
+        constraint = offer.active
+    )]
+    pub offer: Account<'info, Offer>,
+    
+    #[account(
+        mut,
+        seeds = [b"escrow", offer.marketplace.as_ref(), offer.mint.as_ref(), buyer.key().as_ref()],
+        bump = offer.escrow_bump
+    )]
+    /// CHECK: Program-owned PDA holding the lamports of a SOL offer
+    pub escrow: Option<UncheckedAccount<'info>>,
+    
+    #[account(mut)]
+    pub buyer: Signer<'info>,
+    
+    /// Mint the order is priced in; omitted for SOL
+    pub payment_mint: Option<InterfaceAccount<'info, Mint>>,
+    
+    #[account(
+        mut,
+        constraint = buyer_payment_account.owner == buyer.key()
+    )]
+    pub buyer_payment_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    #[account(
+        mut,
+        seeds = [b"payment_escrow", offer.key().as_ref()],
+        bump
+    )]
+    pub payment_escrow: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    pub payment_token_program: Option<Interface<'info, TokenInterface>>,
+    pub system_program: Program<'info, System>,
+}
+
+#[account]
+pub struct Marketplace {
+    pub authority: Pubkey,
+    pub maker_fee: i64,  // in basis points (e.g., 250 = 2.5%), negative for a rebate
+    pub taker_fee: u64,  // in basis points, charged to whoever fills an order
+    pub max_fee: u64,  // upper bound on both fees, fixed at initialization
+    pub treasury: Pubkey,
+    pub bump: u8,
+    pub payment_mints: Vec<Pubkey>,  // SPL tokens accepted besides SOL
+    pub pending_authority: Option<Pubkey>,
+    pub creator: Pubkey,  // authority at initialization, seeds the PDA with `name`
+    pub name: String,
+    pub referral_fee: u64,  // share of `fee` paid to referrers, in basis points
+}
+
+// Wallet registered by the marketplace authority as eligible for referral fees
+#[account]
+pub struct Referrer {
+    pub marketplace: Pubkey,
+    pub wallet: Pubkey,
+    pub bump: u8,
+}
+
+#[account]
+pub struct Listing {
+    pub marketplace: Pubkey,
+    pub seller: Pubkey,
+    pub mint: Pubkey,
+    pub price: u64,  // Per unit
+    pub active: bool,
+    pub created_at: i64,
+    pub bump: u8,
+    pub vault_bump: u8,
+    pub mode: ListingMode,
+    pub delegate_bump: u8,
+    pub frozen: bool,
+    pub payment_mint: Option<Pubkey>,  // None when priced in lamports
+    pub dutch: Option<DutchAuction>,
+    pub reserved_buyer: Option<Pubkey>,  // Only wallet allowed to buy, if set
+    pub expires_at: i64,
+    pub quantity: u64,  // Units left for sale
+}
+
+impl Listing {
+    // Price a buyer pays at `now`
+    pub fn current_price(&self, now: i64) -> u64 {
+        match self.dutch {
+            Some(dutch) => dutch.price_at(now),
+            None => self.price,
+        }
+    }
+
+    // Whether the listing can no longer be bought at `now`
+    pub fn is_expired(&self, now: i64) -> bool {
+        now > self.expires_at
+    }
+
+    // Units left and total price after buying `quantity` units at `price`
+    pub fn fill(&self, quantity: u64, price: u64) -> Result<(u64, u64)> {
+        require!(quantity > 0, NftMarketplaceError::InvalidQuantity);
+        let remaining = self
+            .quantity
+            .checked_sub(quantity)
+            .ok_or(NftMarketplaceError::InvalidQuantity)?;
+        let total_price = price
+            .checked_mul(quantity)
+            .ok_or(NftMarketplaceError::PriceOverflow)?;
+        Ok((remaining, total_price))
+    }
+}
+
+// Dutch auction terms: the price falls linearly from `start_price` at
+// `start_time` to `floor_price` over `duration` seconds
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct DutchAuction {
+    pub start_price: u64,
+    pub floor_price: u64,
+    pub start_time: i64,
+    pub duration: i64,
+}
+
+impl DutchAuction {
+    pub fn price_at(&self, now: i64) -> u64 {
+        let elapsed = now.saturating_sub(self.start_time).clamp(0, self.duration);
+        let decay = ((self.start_price - self.floor_price) as u128)
+            .checked_mul(elapsed as u128)
+            .unwrap()
+            .checked_div(self.duration as u128)
+            .unwrap() as u64;
+        self.start_price - decay
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ListingMode {
+    // NFT is held in a program-owned vault while listed
+    Escrow,
+    // NFT stays in the seller's wallet with a program PDA as delegate
+    Delegate,
+    // Compressed NFT leaf delegated to a program PDA; `mint` holds the asset id
+    Compressed,
+}
+
+// Leaf of a compressed NFT as currently stored in its Bubblegum merkle tree
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct CompressedLeaf {
+    pub root: [u8; 32],
+    pub data_hash: [u8; 32],
+    pub creator_hash: [u8; 32],
+    pub nonce: u64,
+    pub index: u32,
+}
+
+#[account]
+pub struct Offer {
+    pub marketplace: Pubkey,
+    pub buyer: Pubkey,
+    pub mint: Pubkey,
+    pub price: u64,
+    pub created_at: i64,
+    pub expires_at: i64,
+    pub active: bool,
+    pub bump: u8,
+    pub payment_mint: Option<Pubkey>,  // None when priced in lamports
+    pub escrow_bump: u8,
+    pub maker_fee: i64,  // locked in when made, negative for a rebate
+}
+
+impl Offer {
+    // Amount escrowed for the offer: the price plus the maker fee, less any rebate
+    pub fn escrowed_amount(&self) -> Result<u64> {
+        self.price
+            .checked_add_signed(self.maker_fee)
+            .ok_or_else(|| NftMarketplaceError::EscrowOverflow.into())
+    }
+}
+
+#[account]
+pub struct Bid {
+    pub marketplace: Pubkey,
+    pub bidder: Pubkey,
+    pub mint: Pubkey,
+    pub price: u64,
+    pub created_at: i64,
+    pub expires_at: i64,
+    pub active: bool,
+    pub bump: u8,
+    pub payment_mint: Option<Pubkey>,  // None when priced in lamports
+    pub escrow_bump: u8,
+    pub maker_fee: i64,  // locked in when made, negative for a rebate
+}
+
+impl Bid {
+    // Amount escrowed for the bid: the price plus the maker fee, less any rebate
+    pub fn escrowed_amount(&self) -> Result<u64> {
+        self.price
+            .checked_add_signed(self.maker_fee)
+            .ok_or_else(|| NftMarketplaceError::EscrowOverflow.into())
+    }
+}
+
+#[account]
+pub struct CollectionOffer {
+    pub marketplace: Pubkey,
+    pub buyer: Pubkey,
+    pub collection: Pubkey,
+    pub price: u64,  // per NFT
+    pub quantity: u64,  // NFTs still wanted
+    pub payment_mint: Option<Pubkey>,  // None when priced in lamports
+    pub created_at: i64,
+    pub expires_at: i64,
+    pub bump: u8,
+    pub trait_root: Option<[u8; 32]>,  // snapshot of the trait set root the offer is restricted to
+    pub maker_fee: i64,  // per NFT locked in when made, negative for a rebate
+}
+
+impl CollectionOffer {
+    // Amount escrowed per NFT for the offer: the price plus the maker fee, less any rebate
+    pub fn escrowed_amount(&self) -> Result<u64> {
+        self.price
+            .checked_add_signed(self.maker_fee)
+            .ok_or_else(|| NftMarketplaceError::EscrowOverflow.into())
+    }
+    
+    // Amount escrowed for `quantity` NFTs of the offer
+    pub fn escrowed_total(&self, quantity: u64) -> Result<u64> {
+        self.escrowed_amount()?
+            .checked_mul(quantity)
+            .ok_or_else(|| NftMarketplaceError::EscrowOverflow.into())
+    }
+}
+
+// Several NFTs escrowed in the bundle's token accounts and sold as one lot
+#[account]
+pub struct BundleListing {
+    pub marketplace: Pubkey,
+    pub seller: Pubkey,
+    pub bundle_id: u64,
+    pub mints: Vec<Pubkey>,
+    pub price: u64,
+    pub payment_mint: Option<Pubkey>,  // None when priced in lamports
+    pub created_at: i64,
+    pub bump: u8,
+}
+
+impl BundleListing {
+    // Share of the price attributed to the item at `index`; the last item
+    // takes the rounding remainder
+    pub fn item_price(&self, index: usize) -> u64 {
+        let count = self.mints.len() as u64;
+        let share = self.price / count;
+        if index + 1 == self.mints.len() {
+            share + self.price % count
+        } else {
+            share
+        }
+    }
+}
+
+// NFTs and lamports escrowed by a proposer in exchange for a counterparty's
+// NFTs and lamports
+#[account]
+pub struct Swap {
+    pub marketplace: Pubkey,
+    pub proposer: Pubkey,
+    pub counterparty: Pubkey,
+    pub swap_id: u64,
+    pub offered_mints: Vec<Pubkey>,
+    pub offered_lamports: u64,
+    pub requested_mints: Vec<Pubkey>,
+    pub requested_lamports: u64,
+    pub created_at: i64,
+    pub bump: u8,
+}
+
+// Terms negotiated with a collection, applied to trades of its NFTs
+#[account]
+pub struct CollectionConfig {
+    pub marketplace: Pubkey,
+    pub collection: Pubkey,
+    pub maker_fee: Option<i64>,  // overrides the marketplace maker fee
+    pub taker_fee: Option<u64>,  // overrides the marketplace taker fee
+    pub min_royalty: u16,  // in basis points, raises lower creator royalties
+    pub payment_mints: Vec<Pubkey>,  // accepted currencies if non-empty, `Pubkey::default()` for SOL
+    pub min_price: u64,  // lowest listing price
+    pub bump: u8,
+}
+
+// Returns the minimum royalty enforced by a collection config, in basis points
+fn min_royalty(collection_config: Option<&CollectionConfig>) -> u16 {
+    collection_config.map_or(0, |config| config.min_royalty)
+}
+
+// Merkle root of the mints in a collection having a trait set
+#[account]
+pub struct TraitRoot {
+    pub collection: Pubkey,
+    pub trait_id: [u8; 32],
+    pub root: [u8; 32],
+    pub bump: u8,
+}
+
+#[account]
+pub struct Auction {
+    pub marketplace: Pubkey,
+    pub seller: Pubkey,
+    pub mint: Pubkey,
+    pub payment_mint: Option<Pubkey>,  // None when priced in lamports
+    pub reserve_price: u64,
+    pub min_increment: u64,
+    pub highest_bid: u64,
+    pub highest_bidder: Option<Pubkey>,
+    pub created_at: i64,
+    pub end_time: i64,
+    pub extension: i64,  // anti-sniping window in seconds
+    pub bump: u8,
+    pub vault_bump: u8,
+    pub highest_bid_fee: u64,  // taker fee escrowed with the highest bid
+}
+
+impl Auction {
+    // Lowest amount the next bid may be: the reserve price, or the current bid
+    // plus the minimum increment. A bid that cannot be topped is final.
+    pub fn minimum_bid(&self) -> Result<u64> {
+        match self.highest_bidder {
+            Some(_) => self
+                .highest_bid
+                .checked_add(self.min_increment)
+                .ok_or_else(|| NftMarketplaceError::BidTooLow.into()),
+            None => Ok(self.reserve_price),
+        }
+    }
+
+    // Amount escrowed for the highest bid, including its taker fee
+    pub fn escrowed_amount(&self) -> Result<u64> {
+        self.highest_bid
+            .checked_add(self.highest_bid_fee)
+            .ok_or_else(|| NftMarketplaceError::EscrowOverflow.into())
+    }
+
+    // Marketplace fee and seller's maker fee for settling the highest bid. A
+    // rebate is capped at the taker fee escrowed with the bid, which may
+    // predate a fee change.
+    pub fn settlement_fees(&self, maker_fee_amount: i64) -> Result<(u64, i64)> {
+        let max_rebate = i64::try_from(self.highest_bid_fee).unwrap_or(i64::MAX);
+        let maker_fee_amount = maker_fee_amount.max(-max_rebate);
+        let marketplace_fee_amount = self
+            .highest_bid_fee
+            .checked_add_signed(maker_fee_amount)
+            .ok_or(NftMarketplaceError::RebateExceedsTakerFee)?;
+        Ok((marketplace_fee_amount, maker_fee_amount))
+    }
+}
+
+#[derive(Accounts)]
+#[instruction(price: u64, expiry_time: i64)]
+pub struct PlaceBid<'info> {
+    #[account(
+        init,
+        payer = bidder,
+        space = 8 + 32 + 32 + 32 + 8 + 8 + 8 + 1 + 1 + 1 + 32 + 1 + 8,
+        seeds = [b"bid", marketplace.key().as_ref(), mint.key().as_ref(), bidder.key().as_ref()],
+        bump
+    )]
+    pub bid: Account<'info, Bid>,
+    
+    #[account(
+        init,
+        payer = bidder,
+        space = 0,
+        seeds = [b"bid_escrow", marketplace.key().as_ref(), mint.key().as_ref(), bidder.key().as_ref()],
+        bump
+    )]
+    /// CHECK: Program-owned PDA holding the lamports of a SOL bid
+    pub escrow: Option<UncheckedAccount<'info>>,
+    
+    #[account(mut)]
+    pub bidder: Signer<'info>,
+    
+    pub mint: InterfaceAccount<'info, Mint>,
+    
+    #[account(
+        seeds = [b"metadata", mpl_token_metadata::ID.as_ref(), mint.key().as_ref()],
+        seeds::program = mpl_token_metadata::ID,
+        bump
+    )]
+    /// CHECK: Metadata PDA of the mint, deserialized by the instruction
+    pub metadata: UncheckedAccount<'info>,
+    
+    /// CHECK: Config PDA of the NFT's verified collection, validated by the instruction
+    pub collection_config: Option<UncheckedAccount<'info>>,
+    
+    #[account(
+        seeds = [b"marketplace", marketplace.creator.as_ref(), marketplace.name.as_bytes()],
+        bump = marketplace.bump
+    )]
+    pub marketplace: Account<'info, Marketplace>,
+    
+    /// Mint the order is priced in; omitted for SOL
+    pub payment_mint: Option<InterfaceAccount<'info, Mint>>,
+    
+    #[account(
+        mut,
+        constraint = bidder_payment_account.owner == bidder.key()
+    )]
+    pub bidder_payment_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    #[account(
+        init,
+        payer = bidder,
+        seeds = [b"payment_escrow", bid.key().as_ref()],
+        bump,
+        token::mint = payment_mint,
+        token::authority = bid,
+        token::token_program = payment_token_program
+    )]
+    pub payment_escrow: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    pub payment_token_program: Option<Interface<'info, TokenInterface>>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptBid<'info> {
+    #[account(
+        seeds = [b"marketplace", marketplace.creator.as_ref(), marketplace.name.as_bytes()],
+        bump = marketplace.bump,
+        has_one = treasury
+    )]
+    pub marketplace: Account<'info, Marketplace>,
+    
+    #[account(
+        mut,
+        seeds = [b"bid", bid.marketplace.as_ref(), bid.mint.as_ref(), bid.bidder.as_ref()],
+        bump = bid.bump,
+        has_one = marketplace,
+        close = bidder,
+        constraint = bid.active
+    )]
+    pub bid: Account<'info, Bid>,
+    
+    #[account(
+        mut,
+        seeds = [b"bid_escrow", bid.marketplace.as_ref(), bid.mint.as_ref(), bid.bidder.as_ref()],
+        bump = bid.escrow_bump
+    )]
+    /// CHECK: Program-owned PDA holding the lamports of a SOL bid
+    pub escrow: Option<UncheckedAccount<'info>>,
+    
+    #[account(mut)]
+    pub seller: Signer<'info>,
+    
+    #[account(mut, address = bid.bidder)]
+    /// CHECK: This is the bidder account
+    pub bidder: AccountInfo<'info>,
+    
+    #[account(mut)]
+    /// CHECK: This is the treasury account
+    pub treasury: AccountInfo<'info>,
+    
+    /// Mint the order is priced in; omitted for SOL
+    pub payment_mint: Option<InterfaceAccount<'info, Mint>>,
+    
+    #[account(
+        mut,
+        seeds = [b"payment_escrow", bid.key().as_ref()],
+        bump
+    )]
+    pub payment_escrow: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    #[account(
+        mut,
+        constraint = seller_payment_account.owner == seller.key()
+    )]
+    pub seller_payment_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    #[account(
+        mut,
+        constraint = treasury_payment_account.owner == marketplace.treasury
+    )]
+    pub treasury_payment_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    #[account(mut)]
+    /// CHECK: Wallet of the front-end or aggregator routing the trade
+    pub referrer: Option<UncheckedAccount<'info>>,
+    
+    /// Registration of `referrer` on the marketplace, required to pay it
+    pub registered_referrer: Option<Account<'info, Referrer>>,
+    
+    #[account(mut)]
+    pub referrer_payment_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    pub payment_token_program: Option<Interface<'info, TokenInterface>>,
+    
+    #[account(
+        mut,
+        constraint = seller_token_account.owner == seller.key(),
+        constraint = seller_token_account.mint == bid.mint,
+        constraint = seller_token_account.amount == 1
+    )]
+    pub seller_token_account: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        init_if_needed,
+        payer = seller,
+        associated_token::mint = mint,
+        associated_token::authority = bidder,
+        associated_token::token_program = token_program
+    )]
+    pub bidder_token_account: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        seeds = [b"metadata", mpl_token_metadata::ID.as_ref(), bid.mint.as_ref()],
+        seeds::program = mpl_token_metadata::ID,
+        bump
+    )]
+    /// CHECK: Metadata PDA of the mint, deserialized by the instruction
+    pub metadata: UncheckedAccount<'info>,
+    
+    /// CHECK: Config PDA of the NFT's verified collection, validated by the instruction
+    pub collection_config: Option<UncheckedAccount<'info>>,
+    
+    #[account(address = bid.mint)]
+    pub mint: InterfaceAccount<'info, Mint>,
+    
+    /// CHECK: Master edition of the mint, validated by Token Metadata
+    pub edition: Option<UncheckedAccount<'info>>,
+    
+    #[account(mut)]
+    /// CHECK: Token record of the source token account, validated by Token Metadata
+    pub owner_token_record: Option<UncheckedAccount<'info>>,
+    
+    #[account(mut)]
+    /// CHECK: Token record of the destination token account, validated by Token Metadata
+    pub destination_token_record: Option<UncheckedAccount<'info>>,
+    
+    /// CHECK: Token Authorization Rules program, validated by Token Metadata
+    pub authorization_rules_program: Option<UncheckedAccount<'info>>,
+    
+    /// CHECK: Authorization rule set of the mint, validated by Token Metadata
+    pub authorization_rules: Option<UncheckedAccount<'info>>,
+    
+    #[account(address = anchor_lang::solana_program::sysvar::instructions::ID)]
+    /// CHECK: This is the instructions sysvar
+    pub sysvar_instructions: Option<UncheckedAccount<'info>>,
+    
+    #[account(address = mpl_token_metadata::ID)]
+    /// CHECK: This is the Token Metadata program
+    pub token_metadata_program: Option<UncheckedAccount<'info>>,
+    
+    pub token_program: Interface<'info, TokenInterface>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct CancelBid<'info> {
+    #[account(
+        mut,
+        seeds = [b"bid", bid.marketplace.as_ref(), bid.mint.as_ref(), bidder.key().as_ref()],
+        bump = bid.bump,
+        close = bidder,
+        constraint = bid.bidder == bidder.key(),
+        constraint = bid.active
+    )]
+    pub bid: Account<'info, Bid>,
+    
+    #[account(
+        mut,
+        seeds = [b"bid_escrow", bid.marketplace.as_ref(), bid.mint.as_ref(), bid.bidder.as_ref()],
+        bump = bid.escrow_bump
+    )]
+    /// CHECK: Program-owned PDA holding the lamports of a SOL bid
+    pub escrow: Option<UncheckedAccount<'info>>,
+    
+    #[account(mut)]
+    pub bidder: Signer<'info>,
+    
+    /// Mint the order is priced in; omitted for SOL
+    pub payment_mint: Option<InterfaceAccount<'info, Mint>>,
+    
+    #[account(
+        mut,
+        constraint = bidder_payment_account.owner == bidder.key()
+    )]
+    pub bidder_payment_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    #[account(
+        mut,
+        seeds = [b"payment_escrow", bid.key().as_ref()],
+        bump
+    )]
+    pub payment_escrow: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    pub payment_token_program: Option<Interface<'info, TokenInterface>>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CreateAuction<'info> {
+    #[account(
+        init,
+        payer = seller,
+        space = 8 + 32 + 32 + 32 + 1 + 32 + 8 + 8 + 8 + 1 + 32 + 8 + 8 + 8 + 1 + 1 + 8,
+        seeds = [b"auction", marketplace.key().as_ref(), mint.key().as_ref(), seller.key().as_ref()],
+        bump
+    )]
+    pub auction: Account<'info, Auction>,
+    
+    #[account(
+        seeds = [b"marketplace", marketplace.creator.as_ref(), marketplace.name.as_bytes()],
+        bump = marketplace.bump
+    )]
+    pub marketplace: Account<'info, Marketplace>,
+    
+    #[account(mut)]
+    pub seller: Signer<'info>,
+    
+    pub mint: InterfaceAccount<'info, Mint>,
+    
+    #[account(
+        mut,
+        constraint = seller_token_account.owner == seller.key(),
+        constraint = seller_token_account.mint == mint.key(),
+        constraint = seller_token_account.amount == 1
+    )]
+    pub seller_token_account: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        init,
+        payer = seller,
+        seeds = [b"vault", auction.key().as_ref()],
+        bump,
+        token::mint = mint,
+        token::authority = vault,
+        token::token_program = token_program
+    )]
+    pub vault: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        seeds = [b"metadata", mpl_token_metadata::ID.as_ref(), mint.key().as_ref()],
+        seeds::program = mpl_token_metadata::ID,
+        bump
+    )]
+    /// CHECK: Metadata PDA of the mint, deserialized by the instruction
+    pub metadata: UncheckedAccount<'info>,
+    
+    /// CHECK: Config PDA of the NFT's verified collection, validated by the instruction
+    pub collection_config: Option<UncheckedAccount<'info>>,
+    
+    /// Mint the auction is priced in; omitted for SOL
+    pub payment_mint: Option<InterfaceAccount<'info, Mint>>,
+    
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct PlaceAuctionBid<'info> {
+    #[account(
+        mut,
+        seeds = [b"auction", auction.marketplace.as_ref(), auction.mint.as_ref(), auction.seller.as_ref()],
+        bump = auction.bump,
+        has_one = marketplace
+    )]
+    pub auction: Account<'info, Auction>,
+    
+    #[account(
+        seeds = [b"marketplace", marketplace.creator.as_ref(), marketplace.name.as_bytes()],
+        bump = marketplace.bump
+    )]
+    pub marketplace: Account<'info, Marketplace>,
+    
+    #[account(mut)]
+    pub bidder: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = Some(previous_bidder.key()) == auction.highest_bidder
+    )]
+    /// CHECK: Current highest bidder, refunded by the instruction
+    pub previous_bidder: Option<UncheckedAccount<'info>>,
+    
+    #[account(
+        seeds = [b"metadata", mpl_token_metadata::ID.as_ref(), auction.mint.as_ref()],
+        seeds::program = mpl_token_metadata::ID,
+        bump
+    )]
+    /// CHECK: Metadata PDA of the mint, deserialized by the instruction
+    pub metadata: UncheckedAccount<'info>,
+    
+    /// CHECK: Config PDA of the NFT's verified collection, validated by the instruction
+    pub collection_config: Option<UncheckedAccount<'info>>,
+    
+    /// Mint the auction is priced in; omitted for SOL
+    pub payment_mint: Option<InterfaceAccount<'info, Mint>>,
+    
+    #[account(
+        mut,
+        constraint = bidder_payment_account.owner == bidder.key()
+    )]
+    pub bidder_payment_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    #[account(
+        mut,
+        constraint = Some(previous_bidder_payment_account.owner) == auction.highest_bidder
+    )]
+    pub previous_bidder_payment_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    #[account(
+        init_if_needed,
+        payer = bidder,
+        seeds = [b"payment_escrow", auction.key().as_ref()],
+        bump,
+        token::mint = payment_mint,
+        token::authority = auction,
+        token::token_program = payment_token_program
+    )]
+    pub payment_escrow: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    pub payment_token_program: Option<Interface<'info, TokenInterface>>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SettleAuction<'info> {
+    #[account(
+        mut,
+        seeds = [b"auction", auction.marketplace.as_ref(), auction.mint.as_ref(), auction.seller.as_ref()],
+        bump = auction.bump,
+        has_one = marketplace,
+        close = seller
+    )]
+    pub auction: Account<'info, Auction>,
+    
+    #[account(
+        seeds = [b"marketplace", marketplace.creator.as_ref(), marketplace.name.as_bytes()],
+        bump = marketplace.bump,
+        has_one = treasury
+    )]
+    pub marketplace: Account<'info, Marketplace>,
+    
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    
+    #[account(mut, address = auction.seller)]
+    /// CHECK: This is the seller account
+    pub seller: AccountInfo<'info>,
+    
+    #[account(mut)]
+    /// CHECK: This is the treasury account
+    pub treasury: AccountInfo<'info>,
+    
+    #[account(constraint = Some(winner.key()) == auction.highest_bidder)]
+    /// CHECK: Highest bidder of the auction
+    pub winner: Option<UncheckedAccount<'info>>,
+    
+    #[account(
+        mut,
+        seeds = [b"vault", auction.key().as_ref()],
+        bump = auction.vault_bump
+    )]
+    pub vault: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        init_if_needed,
+        payer = payer,
+        associated_token::mint = mint,
+        associated_token::authority = winner,
+        associated_token::token_program = token_program
+    )]
+    pub winner_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
     
     #[account(
         mut,
-        constraint = buyer_token_account.owner == buyer.key(),
-        constraint = buyer_token_account.mint == offer.mint
+        constraint = seller_token_account.owner == seller.key(),
+        constraint = seller_token_account.mint == auction.mint
+    )]
+    pub seller_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    #[account(address = auction.mint)]
+    pub mint: InterfaceAccount<'info, Mint>,
+    
+    #[account(
+        seeds = [b"metadata", mpl_token_metadata::ID.as_ref(), auction.mint.as_ref()],
+        seeds::program = mpl_token_metadata::ID,
+        bump
+    )]
+    /// CHECK: Metadata PDA of the mint, deserialized by the instruction
+    pub metadata: UncheckedAccount<'info>,
+    
+    /// CHECK: Config PDA of the NFT's verified collection, validated by the instruction
+    pub collection_config: Option<UncheckedAccount<'info>>,
+    
+    /// Mint the auction is priced in; omitted for SOL
+    pub payment_mint: Option<InterfaceAccount<'info, Mint>>,
+    
+    #[account(
+        mut,
+        seeds = [b"payment_escrow", auction.key().as_ref()],
+        bump
+    )]
+    pub payment_escrow: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    #[account(
+        mut,
+        constraint = seller_payment_account.owner == seller.key()
+    )]
+    pub seller_payment_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    #[account(
+        mut,
+        constraint = treasury_payment_account.owner == marketplace.treasury
     )]
-    pub buyer_token_account: Account<'info, TokenAccount>,
+    pub treasury_payment_account: Option<InterfaceAccount<'info, TokenAccount>>,
     
-    pub token_program: Program<'info, Token>,
+    pub payment_token_program: Option<Interface<'info, TokenInterface>>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
     pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct CancelOffer<'info> {
+pub struct MakeCollectionOffer<'info> {
+    #[account(
+        init,
+        payer = buyer,
+        space = 8 + 32 + 32 + 32 + 8 + 8 + 1 + 32 + 8 + 8 + 1 + 1 + 32 + 8,
+        seeds = [b"collection_offer", marketplace.key().as_ref(), collection.key().as_ref(), buyer.key().as_ref()],
+        bump
+    )]
+    pub collection_offer: Account<'info, CollectionOffer>,
+    
+    #[account(
+        seeds = [b"marketplace", marketplace.creator.as_ref(), marketplace.name.as_bytes()],
+        bump = marketplace.bump
+    )]
+    pub marketplace: Account<'info, Marketplace>,
+    
+    #[account(mut)]
+    pub buyer: Signer<'info>,
+    
+    /// Mint of the Metaplex collection NFT
+    pub collection: InterfaceAccount<'info, Mint>,
+    
+    #[account(constraint = trait_root.collection == collection.key())]
+    pub trait_root: Option<Account<'info, TraitRoot>>,
+    
+    /// CHECK: Config PDA of the collection, validated by the instruction
+    pub collection_config: Option<UncheckedAccount<'info>>,
+    
+    /// Mint the offer is priced in; omitted for SOL
+    pub payment_mint: Option<InterfaceAccount<'info, Mint>>,
+    
     #[account(
         mut,
-        seeds = [b"offer", offer.mint.as_ref(), buyer.key().as_ref()],
-        bump = offer.bump,
-        constraint = offer.buyer == buyer.key(),
-        constraint = offer.active
+        constraint = buyer_payment_account.owner == buyer.key()
     )]
-    pub offer: Account<'info, Offer>,
+    pub buyer_payment_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    #[account(
+        init,
+        payer = buyer,
+        seeds = [b"payment_escrow", collection_offer.key().as_ref()],
+        bump,
+        token::mint = payment_mint,
+        token::authority = collection_offer,
+        token::token_program = payment_token_program
+    )]
+    pub payment_escrow: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    pub payment_token_program: Option<Interface<'info, TokenInterface>>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptCollectionOffer<'info> {
+    #[account(
+        seeds = [b"marketplace", marketplace.creator.as_ref(), marketplace.name.as_bytes()],
+        bump = marketplace.bump,
+        has_one = treasury
+    )]
+    pub marketplace: Account<'info, Marketplace>,
+    
+    #[account(
+        mut,
+        seeds = [b"collection_offer", collection_offer.marketplace.as_ref(), collection_offer.collection.as_ref(), collection_offer.buyer.as_ref()],
+        bump = collection_offer.bump,
+        has_one = marketplace
+    )]
+    pub collection_offer: Account<'info, CollectionOffer>,
+    
+    #[account(mut)]
+    pub seller: Signer<'info>,
+    
+    #[account(mut, address = collection_offer.buyer)]
+    /// CHECK: This is the buyer account
+    pub buyer: AccountInfo<'info>,
+    
+    #[account(mut)]
+    /// CHECK: This is the treasury account
+    pub treasury: AccountInfo<'info>,
+    
+    /// Mint the offer is priced in; omitted for SOL
+    pub payment_mint: Option<InterfaceAccount<'info, Mint>>,
+    
+    #[account(
+        mut,
+        seeds = [b"payment_escrow", collection_offer.key().as_ref()],
+        bump
+    )]
+    pub payment_escrow: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    #[account(
+        mut,
+        constraint = seller_payment_account.owner == seller.key()
+    )]
+    pub seller_payment_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    #[account(
+        mut,
+        constraint = treasury_payment_account.owner == marketplace.treasury
+    )]
+    pub treasury_payment_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    pub payment_token_program: Option<Interface<'info, TokenInterface>>,
+    
+    #[account(
+        mut,
+        constraint = seller_token_account.owner == seller.key(),
+        constraint = seller_token_account.mint == mint.key(),
+        constraint = seller_token_account.amount == 1
+    )]
+    pub seller_token_account: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        init_if_needed,
+        payer = seller,
+        associated_token::mint = mint,
+        associated_token::authority = buyer,
+        associated_token::token_program = token_program
+    )]
+    pub buyer_token_account: InterfaceAccount<'info, TokenAccount>,
     
     #[account(
         mut,
-        seeds = [b"escrow", offer.mint.as_ref(), buyer.key().as_ref()],
+        seeds = [b"metadata", mpl_token_metadata::ID.as_ref(), mint.key().as_ref()],
+        seeds::program = mpl_token_metadata::ID,
         bump
     )]
-    /// CHECK: This is the escrow account
-    pub escrow: AccountInfo<'info>,
+    /// CHECK: Metadata PDA of the mint, deserialized by the instruction
+    pub metadata: UncheckedAccount<'info>,
+    
+    /// CHECK: Config PDA of the NFT's verified collection, validated by the instruction
+    pub collection_config: Option<UncheckedAccount<'info>>,
+    
+    pub mint: InterfaceAccount<'info, Mint>,
+    
+    /// CHECK: Master edition of the mint, validated by Token Metadata
+    pub edition: Option<UncheckedAccount<'info>>,
     
     #[account(mut)]
-    pub buyer: Signer<'info>,
+    /// CHECK: Token record of the source token account, validated by Token Metadata
+    pub owner_token_record: Option<UncheckedAccount<'info>>,
+    
+    #[account(mut)]
+    /// CHECK: Token record of the destination token account, validated by Token Metadata
+    pub destination_token_record: Option<UncheckedAccount<'info>>,
+    
+    /// CHECK: Token Authorization Rules program, validated by Token Metadata
+    pub authorization_rules_program: Option<UncheckedAccount<'info>>,
+    
+    /// CHECK: Authorization rule set of the mint, validated by Token Metadata
+    pub authorization_rules: Option<UncheckedAccount<'info>>,
+    
+    #[account(address = anchor_lang::solana_program::sysvar::instructions::ID)]
+    /// CHECK: This is the instructions sysvar
+    pub sysvar_instructions: Option<UncheckedAccount<'info>>,
+    
+    #[account(address = mpl_token_metadata::ID)]
+    /// CHECK: This is the Token Metadata program
+    pub token_metadata_program: Option<UncheckedAccount<'info>>,
     
+    pub token_program: Interface<'info, TokenInterface>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
     pub system_program: Program<'info, System>,
 }
 
-#[account]
-pub struct Marketplace {
-    pub authority: Pubkey,
-    pub fee: u64,  // in basis points (e.g., 250 = 2.5%)
-    pub treasury: Pubkey,
-    pub bump: u8,
+#[derive(Accounts)]
+#[instruction(trait_id: [u8; 32])]
+pub struct PublishTraitRoot<'info> {
+    #[account(
+        init_if_needed,
+        payer = authority,
+        space = 8 + 32 + 32 + 32 + 1,
+        seeds = [b"trait_root", collection.key().as_ref(), trait_id.as_ref()],
+        bump
+    )]
+    pub trait_root: Account<'info, TraitRoot>,
+    
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    
+    /// Mint of the Metaplex collection NFT
+    pub collection: InterfaceAccount<'info, Mint>,
+    
+    #[account(
+        seeds = [b"metadata", mpl_token_metadata::ID.as_ref(), collection.key().as_ref()],
+        seeds::program = mpl_token_metadata::ID,
+        bump
+    )]
+    /// CHECK: Metadata PDA of the collection mint, deserialized by the instruction
+    pub collection_metadata: UncheckedAccount<'info>,
+    
+    pub system_program: Program<'info, System>,
 }
 
-#[account]
-pub struct Listing {
-    pub seller: Pubkey,
-    pub mint: Pubkey,
-    pub price: u64,
-    pub active: bool,
-    pub created_at: i64,
-    pub bump: u8,
+#[derive(Accounts)]
+pub struct CancelCollectionOffer<'info> {
+    #[account(
+        mut,
+        seeds = [b"collection_offer", collection_offer.marketplace.as_ref(), collection_offer.collection.as_ref(), buyer.key().as_ref()],
+        bump = collection_offer.bump,
+        close = buyer,
+        constraint = collection_offer.buyer == buyer.key()
+    )]
+    pub collection_offer: Account<'info, CollectionOffer>,
+    
+    #[account(mut)]
+    pub buyer: Signer<'info>,
+    
+    /// Mint the offer is priced in; omitted for SOL
+    pub payment_mint: Option<InterfaceAccount<'info, Mint>>,
+    
+    #[account(
+        mut,
+        seeds = [b"payment_escrow", collection_offer.key().as_ref()],
+        bump
+    )]
+    pub payment_escrow: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    #[account(
+        mut,
+        constraint = buyer_payment_account.owner == buyer.key()
+    )]
+    pub buyer_payment_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    pub payment_token_program: Option<Interface<'info, TokenInterface>>,
+    pub system_program: Program<'info, System>,
 }
 
-#[account]
-pub struct Offer {
-    pub buyer: Pubkey,
-    pub mint: Pubkey,
-    pub price: u64,
-    pub created_at: i64,
-    pub expires_at: i64,
-    pub active: bool,
-    pub bump: u8,
+#[derive(Accounts)]
+pub struct SetCollectionConfig<'info> {
+    #[account(
+        init_if_needed,
+        payer = authority,
+        space = 8 + 32 + 32 + 9 + 9 + 2 + 4 + 32 * MAX_PAYMENT_MINTS + 8 + 1,
+        seeds = [b"collection_config", marketplace.key().as_ref(), collection.key().as_ref()],
+        bump
+    )]
+    pub collection_config: Account<'info, CollectionConfig>,
+    
+    #[account(
+        seeds = [b"marketplace", marketplace.creator.as_ref(), marketplace.name.as_bytes()],
+        bump = marketplace.bump
+    )]
+    pub marketplace: Account<'info, Marketplace>,
+    
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    
+    /// Mint of the Metaplex collection NFT
+    pub collection: InterfaceAccount<'info, Mint>,
+    
+    #[account(
+        seeds = [b"metadata", mpl_token_metadata::ID.as_ref(), collection.key().as_ref()],
+        seeds::program = mpl_token_metadata::ID,
+        bump
+    )]
+    /// CHECK: Metadata PDA of the collection mint, deserialized by the instruction
+    pub collection_metadata: UncheckedAccount<'info>,
+    
+    pub system_program: Program<'info, System>,
 }
 
-#[account]
-pub struct Bid {
-    pub bidder: Pubkey,
-    pub mint: Pubkey,
-    pub price: u64,
-    pub created_at: i64,
-    pub expires_at: i64,
-    pub active: bool,
-    pub bump: u8,
+#[derive(Accounts)]
+#[instruction(bundle_id: u64)]
+pub struct ListBundle<'info> {
+    #[account(
+        init,
+        payer = seller,
+        space = 8 + 32 + 32 + 8 + 4 + 32 * MAX_BUNDLE_SIZE + 8 + 1 + 32 + 8 + 1,
+        seeds = [b"bundle", marketplace.key().as_ref(), seller.key().as_ref(), bundle_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub bundle: Account<'info, BundleListing>,
+    
+    #[account(
+        seeds = [b"marketplace", marketplace.creator.as_ref(), marketplace.name.as_bytes()],
+        bump = marketplace.bump
+    )]
+    pub marketplace: Account<'info, Marketplace>,
+    
+    #[account(mut)]
+    pub seller: Signer<'info>,
+    
+    /// Mint the bundle is priced in; omitted for SOL
+    pub payment_mint: Option<InterfaceAccount<'info, Mint>>,
+    
+    pub token_program: Interface<'info, TokenInterface>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct BuyBundle<'info> {
+    #[account(
+        seeds = [b"marketplace", marketplace.creator.as_ref(), marketplace.name.as_bytes()],
+        bump = marketplace.bump,
+        has_one = treasury
+    )]
+    pub marketplace: Account<'info, Marketplace>,
+    
+    #[account(
+        mut,
+        seeds = [b"bundle", bundle.marketplace.as_ref(), bundle.seller.as_ref(), bundle.bundle_id.to_le_bytes().as_ref()],
+        bump = bundle.bump,
+        has_one = marketplace,
+        close = seller
+    )]
+    pub bundle: Account<'info, BundleListing>,
+    
+    #[account(mut)]
+    pub buyer: Signer<'info>,
+    
+    #[account(mut, address = bundle.seller)]
+    /// CHECK: This is the seller account
+    pub seller: AccountInfo<'info>,
+    
+    #[account(mut)]
+    /// CHECK: This is the treasury account
+    pub treasury: AccountInfo<'info>,
+    
+    /// Mint the bundle is priced in; omitted for SOL
+    pub payment_mint: Option<InterfaceAccount<'info, Mint>>,
+    
+    #[account(
+        mut,
+        constraint = buyer_payment_account.owner == buyer.key()
+    )]
+    pub buyer_payment_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    #[account(
+        mut,
+        constraint = seller_payment_account.owner == seller.key()
+    )]
+    pub seller_payment_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    #[account(
+        mut,
+        constraint = treasury_payment_account.owner == marketplace.treasury
+    )]
+    pub treasury_payment_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    pub payment_token_program: Option<Interface<'info, TokenInterface>>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-#[instruction(price: u64, expiry_time: i64)]
-pub struct PlaceBid<'info> {
+pub struct CancelBundle<'info> {
     #[account(
-        init,
-        payer = bidder,
-        space = 8 + 32 + 32 + 8 + 8 + 8 + 1 + 1,
-        seeds = [b"bid", mint.key().as_ref(), bidder.key().as_ref()],
-        bump
+        mut,
+        seeds = [b"bundle", bundle.marketplace.as_ref(), bundle.seller.as_ref(), bundle.bundle_id.to_le_bytes().as_ref()],
+        bump = bundle.bump,
+        close = seller
     )]
-    pub bid: Account<'info, Bid>,
+    pub bundle: Account<'info, BundleListing>,
+    
+    #[account(mut, address = bundle.seller)]
+    pub seller: Signer<'info>,
     
+    pub token_program: Interface<'info, TokenInterface>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(swap_id: u64)]
+pub struct ProposeSwap<'info> {
     #[account(
         init,
-        payer = bidder,
-        space = 0,
-        seeds = [b"bid_escrow", mint.key().as_ref(), bidder.key().as_ref()],
+        payer = proposer,
+        space = 8 + 32 + 32 + 32 + 8 + 4 + 32 * MAX_SWAP_SIZE + 8 + 4 + 32 * MAX_SWAP_SIZE + 8 + 8 + 1,
+        seeds = [b"swap", marketplace.key().as_ref(), proposer.key().as_ref(), swap_id.to_le_bytes().as_ref()],
         bump
     )]
-    /// CHECK: This is the escrow account
-    pub escrow: AccountInfo<'info>,
+    pub swap: Account<'info, Swap>,
     
-    #[account(mut)]
-    pub bidder: Signer<'info>,
+    #[account(
+        seeds = [b"marketplace", marketplace.creator.as_ref(), marketplace.name.as_bytes()],
+        bump = marketplace.bump
+    )]
+    pub marketplace: Account<'info, Marketplace>,
     
-    pub mint: Account<'info, Mint>,
+    #[account(mut)]
+    pub proposer: Signer<'info>,
     
+    pub token_program: Interface<'info, TokenInterface>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
     pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct AcceptBid<'info> {
+pub struct ExecuteSwap<'info> {
+    #[account(
+        seeds = [b"marketplace", marketplace.creator.as_ref(), marketplace.name.as_bytes()],
+        bump = marketplace.bump,
+        has_one = treasury
+    )]
     pub marketplace: Account<'info, Marketplace>,
     
     #[account(
         mut,
-        seeds = [b"bid", bid.mint.as_ref(), bid.bidder.as_ref()],
-        bump = bid.bump,
-        constraint = bid.active
+        seeds = [b"swap", swap.marketplace.as_ref(), swap.proposer.as_ref(), swap.swap_id.to_le_bytes().as_ref()],
+        bump = swap.bump,
+        has_one = marketplace,
+        close = proposer
     )]
-    pub bid: Account<'info, Bid>,
-    
-    #[account(mut)]
-    /// CHECK: This is the escrow account
-    pub escrow: AccountInfo<'info>,
+    pub swap: Account<'info, Swap>,
     
-    #[account(mut)]
-    pub seller: Signer<'info>,
+    #[account(mut, address = swap.counterparty)]
+    pub counterparty: Signer<'info>,
     
-    #[account(mut)]
-    /// CHECK: This is the bidder account
-    pub bidder: AccountInfo<'info>,
+    #[account(mut, address = swap.proposer)]
+    /// CHECK: This is the proposer account
+    pub proposer: AccountInfo<'info>,
     
     #[account(mut)]
     /// CHECK: This is the treasury account
     pub treasury: AccountInfo<'info>,
     
-    #[account(
-        mut,
-        constraint = seller_token_account.owner == seller.key(),
-        constraint = seller_token_account.mint == bid.mint,
-        constraint = seller_token_account.amount == 1
-    )]
-    pub seller_token_account: Account<'info, TokenAccount>,
-    
-    #[account(
-        init_if_needed,
-        payer = seller,
-        associated_token::mint = mint,
-        associated_token::authority = bidder
-    )]
-    pub bidder_token_account: Account<'info, TokenAccount>,
-    
-    pub mint: Account<'info, Mint>,
-    
-    pub token_program: Program<'info, Token>,
+    pub token_program: Interface<'info, TokenInterface>,
     pub associated_token_program: Program<'info, AssociatedToken>,
     pub system_program: Program<'info, System>,
-    pub rent: Sysvar<'info, Rent>,
 }
 
 #[derive(Accounts)]
-pub struct CancelBid<'info> {
+pub struct CancelSwap<'info> {
     #[account(
         mut,
-        seeds = [b"bid", bid.mint.as_ref(), bidder.key().as_ref()],
-        bump = bid.bump,
-        constraint = bid.bidder == bidder.key(),
-        constraint = bid.active
+        seeds = [b"swap", swap.marketplace.as_ref(), swap.proposer.as_ref(), swap.swap_id.to_le_bytes().as_ref()],
+        bump = swap.bump,
+        close = proposer
     )]
-    pub bid: Account<'info, Bid>,
+    pub swap: Account<'info, Swap>,
     
-    #[account(mut)]
-    /// CHECK: This is the escrow account
-    pub escrow: AccountInfo<'info>,
-    
-    #[account(mut)]
-    pub bidder: Signer<'info>,
+    #[account(mut, address = swap.proposer)]
+    pub proposer: Signer<'info>,
     
+    pub token_program: Interface<'info, TokenInterface>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
     pub system_program: Program<'info, System>,
 }
 
@@ -836,4 +6025,837 @@ pub enum NftMarketplaceError {
     
     #[msg("Only the NFT owner can accept bids")]
     NotNftOwner,
+    
+    #[msg("An account required by the listing mode was not provided")]
+    MissingListingAccount,
+    
+    #[msg("The metadata account does not match the NFT mint")]
+    InvalidMetadata,
+    
+    #[msg("This listing mode is not supported for this asset or instruction")]
+    UnsupportedListingMode,
+    
+    #[msg("An account required to transfer a programmable NFT was not provided")]
+    MissingProgrammableAccount,
+    
+    #[msg("The mint is non-transferable")]
+    NonTransferableMint,
+    
+    #[msg("The asset id does not match the merkle tree and leaf nonce")]
+    InvalidAssetId,
+    
+    #[msg("Creator accounts do not match the verified creators in the metadata")]
+    InvalidCreator,
+    
+    #[msg("The payment mint is not accepted by this marketplace")]
+    PaymentMintNotAllowed,
+    
+    #[msg("The payment mint does not match the order")]
+    InvalidPaymentMint,
+    
+    #[msg("An account required to settle in the payment mint was not provided")]
+    MissingPaymentAccount,
+    
+    #[msg("Too many payment mints")]
+    TooManyPaymentMints,
+    
+    #[msg("The signer is not the pending marketplace authority")]
+    NotPendingAuthority,
+    
+    #[msg("The marketplace fee exceeds the maximum fee")]
+    InvalidFee,
+    
+    #[msg("The auction duration and extension must be positive")]
+    InvalidAuctionDuration,
+    
+    #[msg("The auction has ended")]
+    AuctionEnded,
+    
+    #[msg("The auction has not ended yet")]
+    AuctionNotEnded,
+    
+    #[msg("The Dutch auction start price must be at least its floor and its duration positive")]
+    InvalidDutchAuction,
+    
+    #[msg("The current price exceeds the buyer's maximum price")]
+    PriceAboveMaximum,
+    
+    #[msg("The quantity must be positive")]
+    InvalidQuantity,
+    
+    #[msg("The NFT is not a verified member of the collection")]
+    InvalidCollection,
+    
+    #[msg("The merkle proof does not place the mint in the trait set")]
+    InvalidTraitProof,
+    
+    #[msg("The bundle accounts do not match the bundled NFTs")]
+    InvalidBundle,
+    
+    #[msg("The swap accounts do not match the swapped NFTs")]
+    InvalidSwap,
+    
+    #[msg("The listing is reserved for another buyer")]
+    NotReservedBuyer,
+    
+    #[msg("The listing has expired")]
+    ExpiredListing,
+    
+    #[msg("The listing has not expired yet")]
+    ListingNotExpired,
+    
+    #[msg("Marketplace names must be 1 to 32 bytes long")]
+    InvalidMarketplaceName,
+    
+    #[msg("The referrer payment account is not owned by the referrer")]
+    InvalidReferrerAccount,
+    
+    #[msg("The config account of the NFT's collection is missing or invalid")]
+    MissingCollectionConfig,
+    
+    #[msg("Only the marketplace or collection update authority can configure a collection")]
+    InvalidConfigAuthority,
+    
+    #[msg("Royalties cannot exceed 10000 basis points")]
+    InvalidRoyalty,
+    
+    #[msg("The price is below the collection minimum")]
+    PriceBelowMinimum,
+    
+    #[msg("Royalties and fees exceed the sale price")]
+    FeesExceedPrice,
+    
+    #[msg("The total price overflows")]
+    PriceOverflow,
+    
+    #[msg("The maker rebate locked in by the order exceeds the current taker fee")]
+    RebateExceedsTakerFee,
+    
+    #[msg("The auction minimum increment must be positive")]
+    InvalidMinIncrement,
+    
+    #[msg("The expiry time must be in the future")]
+    InvalidExpiry,
+    
+    #[msg("The referrer is not registered on this marketplace")]
+    UnregisteredReferrer,
+    
+    #[msg("Traders cannot refer their own trades")]
+    SelfReferral,
+    
+    #[msg("The metadata does not match the compressed leaf")]
+    InvalidCompressedMetadata,
+    
+    #[msg("Only the marketplace authority can override collection fees")]
+    FeeOverrideNotAllowed,
+    
+    #[msg("The escrowed amount overflows")]
+    EscrowOverflow,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Backing storage for an `AccountInfo` handed to helpers under test
+    struct TestAccount {
+        key: Pubkey,
+        owner: Pubkey,
+        lamports: u64,
+        data: Vec<u8>,
+    }
+
+    impl TestAccount {
+        fn new(owner: Pubkey, lamports: u64) -> Self {
+            Self {
+                key: Pubkey::new_unique(),
+                owner,
+                lamports,
+                data: Vec::new(),
+            }
+        }
+
+        fn with_data(owner: Pubkey, data: Vec<u8>) -> Self {
+            Self {
+                data,
+                ..Self::new(owner, 1_000_000)
+            }
+        }
+
+        fn info(&mut self) -> AccountInfo<'_> {
+            AccountInfo::new(&self.key, false, true, &mut self.lamports, &mut self.data, &self.owner, false, 0)
+        }
+    }
+
+    // Metaplex metadata of `mint`, owned by Token Metadata
+    fn metadata_account(metadata: Metadata) -> TestAccount {
+        let mut data = vec![0; mpl_token_metadata::state::MAX_METADATA_LEN];
+        metadata.save(&mut data).unwrap();
+        let mut account = TestAccount::with_data(mpl_token_metadata::ID, data);
+        account.key = mpl_token_metadata::pda::find_metadata_account(&metadata.mint).0;
+        account
+    }
+
+    fn metadata(mint: Pubkey, token_standard: Option<TokenStandard>) -> Metadata {
+        Metadata {
+            key: mpl_token_metadata::state::Key::MetadataV1,
+            update_authority: Pubkey::new_unique(),
+            mint,
+            data: mpl_token_metadata::state::Data {
+                name: "NFT".to_string(),
+                symbol: String::new(),
+                uri: String::new(),
+                seller_fee_basis_points: 0,
+                creators: None,
+            },
+            primary_sale_happened: true,
+            is_mutable: true,
+            edition_nonce: None,
+            token_standard,
+            collection: None,
+            uses: None,
+            collection_details: None,
+            programmable_config: None,
+        }
+    }
+
+    // Pays out of a program-owned lamport escrow, as offers and auctions do
+    fn escrow_payer<'a, 'info>(escrow: &AccountInfo<'info>) -> Payer<'a, 'info> {
+        Payer {
+            from: escrow.clone(),
+            authority: escrow.clone(),
+            payment_mint: None,
+            program: escrow.clone(),
+            signer_seeds: &[],
+        }
+    }
+
+    #[test]
+    fn escrow_payments_debit_lamports_directly() {
+        let mut escrow = TestAccount::new(crate::ID, 100);
+        let mut seller = TestAccount::new(System::id(), 5);
+        let escrow_info = escrow.info();
+        let seller_info = seller.info();
+        let payer = escrow_payer(&escrow_info);
+
+        payer.pay(seller_info.clone(), 60).unwrap();
+        payer.pay(seller_info.clone(), 0).unwrap();
+        assert_eq!(escrow_info.lamports(), 40);
+        assert_eq!(seller_info.lamports(), 65);
+    }
+
+    #[test]
+    fn underfunded_escrow_payments_fail_without_moving_lamports() {
+        let mut escrow = TestAccount::new(crate::ID, 40);
+        let mut seller = TestAccount::new(System::id(), 0);
+        let escrow_info = escrow.info();
+        let seller_info = seller.info();
+
+        assert_eq!(
+            escrow_payer(&escrow_info).pay(seller_info.clone(), 41).unwrap_err(),
+            NftMarketplaceError::InsufficientFunds.into()
+        );
+        assert_eq!(escrow_info.lamports(), 40);
+        assert_eq!(seller_info.lamports(), 0);
+    }
+
+    #[test]
+    fn royalties_split_by_creator_share_rounding_down() {
+        let (first, second) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let royalties = royalty_shares(10_001, 500, [(first, 70), (second, 30)].into_iter());
+        assert_eq!(royalties, vec![(first, 350), (second, 150)]);
+        assert!(royalty_shares(10_000, 0, [(first, 100)].into_iter())
+            .iter()
+            .all(|(_, amount)| *amount == 0));
+    }
+
+    #[test]
+    fn royalties_are_paid_to_each_listed_creator() {
+        let mut escrow = TestAccount::new(crate::ID, 1_000);
+        let mut first = TestAccount::new(System::id(), 0);
+        let mut second = TestAccount::new(System::id(), 0);
+        let royalties = [(first.key, 350), (second.key, 150)];
+        let escrow_info = escrow.info();
+        let creators = [first.info(), second.info()];
+
+        pay_royalties(&escrow_payer(&escrow_info), &royalties, &creators).unwrap();
+        assert_eq!(creators[0].lamports(), 350);
+        assert_eq!(creators[1].lamports(), 150);
+        assert_eq!(escrow_info.lamports(), 500);
+    }
+
+    #[test]
+    fn royalties_reject_missing_or_substituted_creators() {
+        let mut escrow = TestAccount::new(crate::ID, 1_000);
+        let mut creator = TestAccount::new(System::id(), 0);
+        let mut impostor = TestAccount::new(System::id(), 0);
+        let royalties = [(creator.key, 100), (Pubkey::new_unique(), 100)];
+        let escrow_info = escrow.info();
+        let payer = escrow_payer(&escrow_info);
+
+        assert_eq!(
+            pay_royalties(&payer, &royalties, &[creator.info()]).unwrap_err(),
+            NftMarketplaceError::InvalidCreator.into()
+        );
+        assert_eq!(
+            pay_royalties(&payer, &royalties[..1], &[impostor.info()]).unwrap_err(),
+            NftMarketplaceError::InvalidCreator.into()
+        );
+    }
+
+    #[test]
+    fn dutch_price_decays_linearly_to_floor() {
+        let dutch = DutchAuction {
+            start_price: 1_000,
+            floor_price: 400,
+            start_time: 100,
+            duration: 60,
+        };
+        assert_eq!(dutch.price_at(50), 1_000);
+        assert_eq!(dutch.price_at(100), 1_000);
+        assert_eq!(dutch.price_at(130), 700);
+        assert_eq!(dutch.price_at(159), 410);
+        assert_eq!(dutch.price_at(160), 400);
+        assert_eq!(dutch.price_at(i64::MAX), 400);
+    }
+
+    #[test]
+    fn dutch_price_rounds_decay_down() {
+        let dutch = DutchAuction {
+            start_price: 10,
+            floor_price: 0,
+            start_time: 0,
+            duration: 3,
+        };
+        assert_eq!(dutch.price_at(1), 7);
+        assert_eq!(dutch.price_at(2), 4);
+    }
+
+    fn listing(mode: ListingMode, expires_at: i64) -> Listing {
+        Listing {
+            marketplace: Pubkey::new_unique(),
+            seller: Pubkey::new_unique(),
+            mint: Pubkey::new_unique(),
+            price: 1_000,
+            active: true,
+            created_at: 0,
+            bump: 255,
+            vault_bump: 255,
+            mode,
+            delegate_bump: 255,
+            frozen: false,
+            payment_mint: None,
+            dutch: None,
+            reserved_buyer: None,
+            expires_at,
+            quantity: 1,
+        }
+    }
+
+    #[test]
+    fn listings_expire_after_their_expiry_time() {
+        for mode in [ListingMode::Escrow, ListingMode::Delegate, ListingMode::Compressed] {
+            let listing = listing(mode, 100);
+            assert!(!listing.is_expired(99));
+            assert!(!listing.is_expired(100));
+            assert!(listing.is_expired(101));
+        }
+    }
+
+    #[test]
+    fn partial_fills_leave_the_rest_of_the_listing_for_sale() {
+        let mut listing = listing(ListingMode::Escrow, 0);
+        listing.quantity = 5;
+        assert_eq!(listing.fill(2, 1_000).unwrap(), (3, 2_000));
+        assert_eq!(listing.fill(5, 1_000).unwrap(), (0, 5_000));
+    }
+
+    #[test]
+    fn fills_reject_invalid_quantities_and_overflowing_prices() {
+        let mut listing = listing(ListingMode::Escrow, 0);
+        listing.quantity = 5;
+        assert_eq!(listing.fill(0, 1_000).unwrap_err(), NftMarketplaceError::InvalidQuantity.into());
+        assert_eq!(listing.fill(6, 1_000).unwrap_err(), NftMarketplaceError::InvalidQuantity.into());
+        assert_eq!(listing.fill(2, u64::MAX).unwrap_err(), NftMarketplaceError::PriceOverflow.into());
+    }
+
+    fn leaf(mint: &Pubkey) -> [u8; 32] {
+        keccak::hash(mint.as_ref()).0
+    }
+
+    fn parent(a: [u8; 32], b: [u8; 32]) -> [u8; 32] {
+        if a <= b {
+            keccak::hashv(&[&a, &b]).0
+        } else {
+            keccak::hashv(&[&b, &a]).0
+        }
+    }
+
+    #[test]
+    fn merkle_proof_places_every_mint_in_the_trait_set() {
+        let mints: Vec<Pubkey> = (0..4).map(|_| Pubkey::new_unique()).collect();
+        let leaves: Vec<[u8; 32]> = mints.iter().map(leaf).collect();
+        let left = parent(leaves[0], leaves[1]);
+        let right = parent(leaves[2], leaves[3]);
+        let root = parent(left, right);
+
+        assert!(verify_merkle_proof(&[leaves[1], right], root, leaves[0]));
+        assert!(verify_merkle_proof(&[leaves[0], right], root, leaves[1]));
+        assert!(verify_merkle_proof(&[leaves[3], left], root, leaves[2]));
+        assert!(verify_merkle_proof(&[leaves[2], left], root, leaves[3]));
+    }
+
+    #[test]
+    fn merkle_proof_rejects_mints_outside_the_trait_set() {
+        let mints: Vec<Pubkey> = (0..2).map(|_| Pubkey::new_unique()).collect();
+        let root = parent(leaf(&mints[0]), leaf(&mints[1]));
+        let outsider = leaf(&Pubkey::new_unique());
+
+        assert!(!verify_merkle_proof(&[leaf(&mints[1])], root, outsider));
+        assert!(!verify_merkle_proof(&[], root, leaf(&mints[0])));
+        assert!(verify_merkle_proof(&[], leaf(&mints[0]), leaf(&mints[0])));
+    }
+
+    fn marketplace(maker_fee: i64, taker_fee: u64) -> Marketplace {
+        Marketplace {
+            authority: Pubkey::new_unique(),
+            maker_fee,
+            taker_fee,
+            max_fee: 1_000,
+            treasury: Pubkey::new_unique(),
+            bump: 255,
+            payment_mints: Vec::new(),
+            pending_authority: None,
+            creator: Pubkey::new_unique(),
+            name: "test".to_string(),
+            referral_fee: 0,
+        }
+    }
+
+    fn collection_config(maker_fee: Option<i64>, taker_fee: Option<u64>) -> CollectionConfig {
+        CollectionConfig {
+            marketplace: Pubkey::new_unique(),
+            collection: Pubkey::new_unique(),
+            maker_fee,
+            taker_fee,
+            min_royalty: 0,
+            payment_mints: Vec::new(),
+            min_price: 0,
+            bump: 255,
+        }
+    }
+
+    #[test]
+    fn trade_fees_charge_both_sides() {
+        assert_eq!(trade_fees(&marketplace(100, 200), None, 10_000), (200, 100));
+        assert_eq!(trade_fees(&marketplace(0, 250), None, 999), (24, 0));
+    }
+
+    #[test]
+    fn trade_fees_cap_maker_rebates_at_the_taker_fee() {
+        assert_eq!(trade_fees(&marketplace(-100, 200), None, 10_000), (200, -100));
+        assert_eq!(trade_fees(&marketplace(-300, 200), None, 10_000), (200, -200));
+    }
+
+    #[test]
+    fn trade_fees_apply_collection_overrides() {
+        let marketplace = marketplace(-100, 200);
+        let taker_override = collection_config(None, Some(50));
+        assert_eq!(trade_fees(&marketplace, Some(&taker_override), 10_000), (50, -50));
+        let maker_override = collection_config(Some(20), None);
+        assert_eq!(trade_fees(&marketplace, Some(&maker_override), 10_000), (200, 20));
+    }
+
+    fn compressed_metadata(seller_fee_basis_points: u16, creators: &[(Pubkey, bool, u8)]) -> MetadataArgs {
+        MetadataArgs {
+            name: "cNFT".to_string(),
+            symbol: String::new(),
+            uri: String::new(),
+            seller_fee_basis_points,
+            primary_sale_happened: true,
+            is_mutable: true,
+            edition_nonce: None,
+            token_standard: None,
+            collection: None,
+            uses: None,
+            token_program_version: mpl_bubblegum::types::TokenProgramVersion::Original,
+            creators: creators
+                .iter()
+                .map(|&(address, verified, share)| mpl_bubblegum::types::Creator {
+                    address,
+                    verified,
+                    share,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn creator_royalties_are_raised_to_the_collection_minimum() {
+        let (first, second) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut mint = TestAccount::new(System::id(), 0);
+        let mut nft = metadata(mint.key, None);
+        nft.data.seller_fee_basis_points = 100;
+        nft.data.creators = Some(
+            [(first, true, 70), (second, true, 30), (Pubkey::new_unique(), false, 0)]
+                .into_iter()
+                .map(|(address, verified, share)| mpl_token_metadata::state::Creator {
+                    address,
+                    verified,
+                    share,
+                })
+                .collect(),
+        );
+        let mut nft = metadata_account(nft);
+        let mut other_mint = TestAccount::new(System::id(), 0);
+        let (nft, mint) = (nft.info(), mint.info());
+
+        assert_eq!(creator_royalties(&nft, &mint, 10_000, 0).unwrap(), [(first, 70), (second, 30)]);
+        assert_eq!(creator_royalties(&nft, &mint, 10_000, 50).unwrap(), [(first, 70), (second, 30)]);
+        assert_eq!(creator_royalties(&nft, &mint, 10_000, 500).unwrap(), [(first, 350), (second, 150)]);
+        assert_eq!(
+            creator_royalties(&nft, &other_mint.info(), 10_000, 0).unwrap_err(),
+            NftMarketplaceError::InvalidMetadata.into()
+        );
+    }
+
+    #[test]
+    fn compressed_royalties_are_raised_to_the_collection_minimum() {
+        let (first, second) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let metadata = compressed_metadata(
+            100,
+            &[(first, true, 70), (second, true, 30), (Pubkey::new_unique(), false, 0)],
+        );
+        assert_eq!(compressed_royalties(&metadata, 10_000, 0), [(first, 70), (second, 30)]);
+        assert_eq!(compressed_royalties(&metadata, 10_000, 500), [(first, 350), (second, 150)]);
+    }
+
+    #[test]
+    fn collection_configs_are_loaded_from_the_collection_pda() {
+        let marketplace = Pubkey::new_unique();
+        let collection = Pubkey::new_unique();
+        let (address, _) = Pubkey::find_program_address(
+            &[b"collection_config", marketplace.as_ref(), collection.as_ref()],
+            &crate::ID,
+        );
+        let mut config = collection_config(Some(10), None);
+        config.min_royalty = 500;
+        let mut data = Vec::new();
+        config.try_serialize(&mut data).unwrap();
+        let mut impostor = TestAccount::with_data(crate::ID, data.clone());
+        let mut configured = TestAccount::with_data(crate::ID, data);
+        configured.key = address;
+        let mut unconfigured = TestAccount::new(System::id(), 0);
+        unconfigured.key = address;
+
+        assert!(load_collection_config(&marketplace, None, None).unwrap().is_none());
+        assert_eq!(
+            load_collection_config(&marketplace, Some(collection), None).err(),
+            Some(NftMarketplaceError::MissingCollectionConfig.into())
+        );
+        assert_eq!(
+            load_collection_config(&marketplace, Some(collection), Some(&impostor.info())).err(),
+            Some(NftMarketplaceError::MissingCollectionConfig.into())
+        );
+        assert!(load_collection_config(&marketplace, Some(collection), Some(&unconfigured.info()))
+            .unwrap()
+            .is_none());
+        let loaded = load_collection_config(&marketplace, Some(collection), Some(&configured.info()))
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded.maker_fee, Some(10));
+        assert_eq!(loaded.min_royalty, 500);
+    }
+
+    #[test]
+    fn only_verified_collections_apply_a_config() {
+        let marketplace = Pubkey::new_unique();
+        let mut nft = metadata(Pubkey::new_unique(), None);
+        let mut cnft = compressed_metadata(0, &[]);
+        for verified in [false, true] {
+            nft.collection = Some(mpl_token_metadata::state::Collection {
+                verified,
+                key: Pubkey::new_unique(),
+            });
+            cnft.collection = Some(mpl_bubblegum::types::Collection {
+                verified,
+                key: Pubkey::new_unique(),
+            });
+            let expected = verified.then(|| NftMarketplaceError::MissingCollectionConfig.into());
+            assert_eq!(
+                super::collection_config(&marketplace, &metadata_account(nft.clone()).info(), None).err(),
+                expected
+            );
+            assert_eq!(compressed_collection_config(&marketplace, &cnft, None).err(), expected);
+        }
+    }
+
+    #[test]
+    fn collection_policies_restrict_payment_mints_and_prices() {
+        let usdc = Pubkey::new_unique();
+        let mut config = collection_config(None, None);
+        assert!(require_collection_payment_mint(Some(&config), Some(usdc)).is_ok());
+        assert!(require_collection_payment_mint(None, Some(usdc)).is_ok());
+
+        config.payment_mints = vec![Pubkey::default()];
+        assert!(require_collection_payment_mint(Some(&config), None).is_ok());
+        assert_eq!(
+            require_collection_payment_mint(Some(&config), Some(usdc)).unwrap_err(),
+            NftMarketplaceError::PaymentMintNotAllowed.into()
+        );
+        config.payment_mints = vec![usdc];
+        assert!(require_collection_payment_mint(Some(&config), Some(usdc)).is_ok());
+        assert_eq!(
+            require_collection_payment_mint(Some(&config), None).unwrap_err(),
+            NftMarketplaceError::PaymentMintNotAllowed.into()
+        );
+
+        config.min_price = 1_000;
+        assert!(require_collection_min_price(Some(&config), 1_000).is_ok());
+        assert!(require_collection_min_price(None, 0).is_ok());
+        assert_eq!(
+            require_collection_min_price(Some(&config), 999).unwrap_err(),
+            NftMarketplaceError::PriceBelowMinimum.into()
+        );
+    }
+
+    #[test]
+    fn sale_proceeds_deduct_fees_and_royalties() {
+        let royalties = [(Pubkey::new_unique(), 300), (Pubkey::new_unique(), 200)];
+        assert_eq!(sale_proceeds(10_000, 100, &royalties).unwrap(), 9_400);
+        assert_eq!(sale_proceeds(10_000, -100, &royalties).unwrap(), 9_600);
+        assert_eq!(sale_proceeds(10_000, 0, &[]).unwrap(), 10_000);
+    }
+
+    #[test]
+    fn sale_proceeds_reject_royalties_and_fees_above_the_price() {
+        let royalties = [(Pubkey::new_unique(), 10_000)];
+        assert_eq!(sale_proceeds(10_000, 0, &royalties).unwrap(), 0);
+        assert_eq!(
+            sale_proceeds(10_000, 1, &royalties).unwrap_err(),
+            NftMarketplaceError::FeesExceedPrice.into()
+        );
+        assert_eq!(
+            sale_proceeds(10_000, 0, &[(Pubkey::new_unique(), 10_001)]).unwrap_err(),
+            NftMarketplaceError::FeesExceedPrice.into()
+        );
+    }
+
+    // Leaks the account so that it can back an `UncheckedAccount<'static>`
+    fn leaked_info(account: TestAccount) -> &'static AccountInfo<'static> {
+        Box::leak(Box::new(Box::leak(Box::new(account)).info()))
+    }
+
+    fn program_account<T: AccountSerialize>(state: &T) -> TestAccount {
+        let mut data = Vec::new();
+        state.try_serialize(&mut data).unwrap();
+        TestAccount::with_data(crate::ID, data)
+    }
+
+    #[test]
+    fn referrers_receive_their_share_of_the_marketplace_fee() {
+        let mut state = marketplace(0, 200);
+        state.referral_fee = 2_000;
+        let mut marketplace_account = program_account(&state);
+        let marketplace_info = marketplace_account.info();
+        let marketplace = Account::<Marketplace>::try_from(&marketplace_info).unwrap();
+        let escrow = leaked_info(TestAccount::new(crate::ID, 1_000));
+        let referrer = leaked_info(TestAccount::new(System::id(), 0));
+        let mut registered_account = program_account(&Referrer {
+            marketplace: marketplace.key(),
+            wallet: referrer.key(),
+            bump: 255,
+        });
+        let registered_info = registered_account.info();
+        let registered = Some(Account::<Referrer>::try_from(&registered_info).unwrap());
+        let payer = escrow_payer(escrow);
+        let trader = Pubkey::new_unique();
+
+        assert_eq!(pay_referral(&payer, &marketplace, 500, &trader, &None, &None, &None).unwrap(), 0);
+        let referrer_account = Some(UncheckedAccount::try_from(referrer));
+        assert_eq!(
+            pay_referral(&payer, &marketplace, 500, &trader, &referrer_account, &registered, &None).unwrap(),
+            100
+        );
+        assert_eq!(referrer.lamports(), 100);
+        assert_eq!(escrow.lamports(), 900);
+    }
+
+    #[test]
+    fn referrals_require_a_registered_referrer_other_than_the_trader() {
+        let mut marketplace_account = program_account(&marketplace(0, 200));
+        let marketplace_info = marketplace_account.info();
+        let marketplace = Account::<Marketplace>::try_from(&marketplace_info).unwrap();
+        let payer = escrow_payer(leaked_info(TestAccount::new(crate::ID, 1_000)));
+        let referrer = leaked_info(TestAccount::new(System::id(), 0));
+        let mut registered_account = program_account(&Referrer {
+            marketplace: marketplace.key(),
+            wallet: referrer.key(),
+            bump: 255,
+        });
+        let mut stranger_account = program_account(&Referrer {
+            marketplace: marketplace.key(),
+            wallet: Pubkey::new_unique(),
+            bump: 255,
+        });
+        let (registered_info, stranger_info) = (registered_account.info(), stranger_account.info());
+        let registered = Some(Account::<Referrer>::try_from(&registered_info).unwrap());
+        let stranger = Some(Account::<Referrer>::try_from(&stranger_info).unwrap());
+        let trader = Pubkey::new_unique();
+        let referrer_key = referrer.key();
+        let referrer = Some(UncheckedAccount::try_from(referrer));
+
+        assert_eq!(
+            pay_referral(&payer, &marketplace, 500, &trader, &referrer, &None, &None).unwrap_err(),
+            NftMarketplaceError::UnregisteredReferrer.into()
+        );
+        assert_eq!(
+            pay_referral(&payer, &marketplace, 500, &trader, &referrer, &stranger, &None).unwrap_err(),
+            NftMarketplaceError::UnregisteredReferrer.into()
+        );
+        assert_eq!(
+            pay_referral(&payer, &marketplace, 500, &referrer_key, &referrer, &registered, &None).unwrap_err(),
+            NftMarketplaceError::SelfReferral.into()
+        );
+    }
+
+    fn auction(reserve_price: u64, min_increment: u64, highest_bid: Option<u64>) -> Auction {
+        Auction {
+            marketplace: Pubkey::new_unique(),
+            seller: Pubkey::new_unique(),
+            mint: Pubkey::new_unique(),
+            payment_mint: None,
+            reserve_price,
+            min_increment,
+            highest_bid: highest_bid.unwrap_or_default(),
+            highest_bidder: highest_bid.map(|_| Pubkey::new_unique()),
+            created_at: 0,
+            end_time: 0,
+            extension: 0,
+            bump: 255,
+            vault_bump: 255,
+            highest_bid_fee: 0,
+        }
+    }
+
+    #[test]
+    fn first_auction_bid_must_meet_the_reserve() {
+        assert_eq!(auction(500, 10, None).minimum_bid().unwrap(), 500);
+        assert_eq!(auction(0, 10, None).minimum_bid().unwrap(), 0);
+    }
+
+    #[test]
+    fn later_auction_bids_must_beat_the_leader_by_the_increment() {
+        assert_eq!(auction(500, 10, Some(700)).minimum_bid().unwrap(), 710);
+        assert_eq!(
+            auction(500, 10, Some(u64::MAX - 5)).minimum_bid().unwrap_err(),
+            NftMarketplaceError::BidTooLow.into()
+        );
+    }
+
+    #[test]
+    fn auction_winners_pay_the_taker_fee_on_top_of_their_bid() {
+        let marketplace = marketplace(100, 200);
+        let mut auction = auction(500, 10, Some(10_000));
+        let (taker_fee_amount, maker_fee_amount) = trade_fees(&marketplace, None, auction.highest_bid);
+        auction.highest_bid_fee = taker_fee_amount;
+        assert_eq!(auction.escrowed_amount().unwrap(), 10_200);
+
+        let (marketplace_fee_amount, maker_fee_amount) = auction.settlement_fees(maker_fee_amount).unwrap();
+        assert_eq!((marketplace_fee_amount, maker_fee_amount), (300, 100));
+        let royalties = [(Pubkey::new_unique(), 500)];
+        let seller_amount = sale_proceeds(auction.highest_bid, maker_fee_amount, &royalties).unwrap();
+        assert_eq!(seller_amount + marketplace_fee_amount + 500, auction.escrowed_amount().unwrap());
+    }
+
+    #[test]
+    fn auction_rebates_are_capped_at_the_escrowed_taker_fee() {
+        let mut auction = auction(500, 10, Some(10_000));
+        auction.highest_bid_fee = 200;
+        assert_eq!(auction.settlement_fees(-100).unwrap(), (100, -100));
+        assert_eq!(auction.settlement_fees(-500).unwrap(), (0, -200));
+
+        auction.highest_bid = u64::MAX;
+        assert_eq!(
+            auction.escrowed_amount().unwrap_err(),
+            NftMarketplaceError::EscrowOverflow.into()
+        );
+    }
+
+    fn collection_offer(price: u64, maker_fee: i64) -> CollectionOffer {
+        CollectionOffer {
+            marketplace: Pubkey::new_unique(),
+            buyer: Pubkey::new_unique(),
+            collection: Pubkey::new_unique(),
+            price,
+            quantity: 3,
+            payment_mint: None,
+            created_at: 0,
+            expires_at: 0,
+            bump: 255,
+            trait_root: None,
+            maker_fee,
+        }
+    }
+
+    #[test]
+    fn collection_offers_escrow_the_maker_fee_for_every_unit() {
+        assert_eq!(collection_offer(1_000, 25).escrowed_total(3).unwrap(), 3_075);
+        assert_eq!(collection_offer(1_000, -25).escrowed_total(3).unwrap(), 2_925);
+        assert_eq!(collection_offer(1_000, 25).escrowed_total(0).unwrap(), 0);
+    }
+
+    #[test]
+    fn collection_offers_reject_overflowing_escrows() {
+        assert_eq!(
+            collection_offer(u64::MAX / 2, 0).escrowed_total(3).unwrap_err(),
+            NftMarketplaceError::EscrowOverflow.into()
+        );
+        assert_eq!(
+            collection_offer(u64::MAX, 1).escrowed_amount().unwrap_err(),
+            NftMarketplaceError::EscrowOverflow.into()
+        );
+    }
+
+    #[test]
+    fn programmable_nfts_are_told_apart_from_plain_nfts() {
+        let mint = Pubkey::new_unique();
+        let mut pnft = metadata_account(metadata(mint, Some(TokenStandard::ProgrammableNonFungible)));
+        let mut nft = metadata_account(metadata(mint, Some(TokenStandard::NonFungible)));
+        let mut legacy = metadata_account(metadata(mint, None));
+        let mut missing = TestAccount::new(System::id(), 0);
+
+        assert!(is_programmable(&pnft.info()).unwrap());
+        assert!(!is_programmable(&nft.info()).unwrap());
+        assert!(!is_programmable(&legacy.info()).unwrap());
+        assert!(!is_programmable(&missing.info()).unwrap());
+    }
+
+    fn bundle(price: u64, count: usize) -> BundleListing {
+        BundleListing {
+            marketplace: Pubkey::new_unique(),
+            seller: Pubkey::new_unique(),
+            bundle_id: 0,
+            mints: (0..count).map(|_| Pubkey::new_unique()).collect(),
+            price,
+            payment_mint: None,
+            created_at: 0,
+            bump: 255,
+        }
+    }
+
+    #[test]
+    fn bundle_item_prices_give_the_remainder_to_the_last_item() {
+        let bundle = bundle(1_000, 3);
+        let prices: Vec<u64> = (0..3).map(|index| bundle.item_price(index)).collect();
+        assert_eq!(prices, [333, 333, 334]);
+        assert_eq!(prices.iter().sum::<u64>(), 1_000);
+    }
+
+    #[test]
+    fn bundle_item_prices_split_evenly_without_a_remainder() {
+        let even = bundle(900, 3);
+        assert!((0..3).all(|index| even.item_price(index) == 300));
+        assert_eq!(bundle(7, 1).item_price(0), 7);
+    }
 }